@@ -0,0 +1,238 @@
+//! This module provides `CountingAllocator`, a wrapper around the system
+//! allocator which counts allocations, and `measure`, which reports them
+//! together with the elapsed time of one benchmark phase.
+//!
+//! Counters are global, so a measurement covers the allocations of every
+//! thread, including the worker threads a phase spawns, e.g. the writer of
+//! a pipe. It also covers any other thread allocating at the same time, and
+//! concurrent measurements reset each other's peak, so results are only
+//! valid while nothing else runs: benchmark phases run one after another and
+//! tests calling [measure] hold [MEASURE_LOCK]. [thread_allocations] counts
+//! the calling thread only, for checks which have to be exact under the
+//! parallel test runner.
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    cell::Cell,
+    fmt,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{Duration, Instant},
+};
+
+/// Global allocator which forwards to [System] and counts the allocations
+/// of all threads. Register it with `#[global_allocator]` to enable the
+/// accounting.
+pub struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+static BYTES: AtomicUsize = AtomicUsize::new(0);
+static LIVE: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    // Const initialized, so accessing it from the allocator does not
+    // allocate.
+    static THREAD_ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Serializes the tests calling [measure].
+#[cfg(test)]
+pub static MEASURE_LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+fn count_allocation() {
+    ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+    // Fails once the thread is torn down.
+    let _ = THREAD_ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+}
+
+fn record_alloc(size: usize) {
+    count_allocation();
+    BYTES.fetch_add(size, Ordering::Relaxed);
+    record_live(size, 0);
+}
+
+fn record_live(allocated: usize, freed: usize) {
+    if allocated >= freed {
+        let now = LIVE.fetch_add(allocated - freed, Ordering::Relaxed) + (allocated - freed);
+        PEAK.fetch_max(now, Ordering::Relaxed);
+    } else {
+        LIVE.fetch_sub(freed - allocated, Ordering::Relaxed);
+    }
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            record_alloc(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc_zeroed(layout);
+        if !ptr.is_null() {
+            record_alloc(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        record_live(0, layout.size());
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_ptr = System.realloc(ptr, layout, new_size);
+        if !new_ptr.is_null() {
+            // A reallocation counts as one allocation of the new size.
+            count_allocation();
+            BYTES.fetch_add(new_size, Ordering::Relaxed);
+            record_live(new_size, layout.size());
+        }
+        new_ptr
+    }
+}
+
+/// A step of a benchmark case.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    /// Building the node in memory.
+    Fill,
+    /// Encoding the node.
+    Serialize,
+    /// Checking the encoded bytes (rkyv only).
+    Validate,
+    /// Decoding the node.
+    Deserialize,
+    /// Copying an archived node into a `Type_B` (rkyv only).
+    Convert,
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Phase::Fill => "fill",
+            Phase::Serialize => "serialize",
+            Phase::Validate => "validate",
+            Phase::Deserialize => "deserialize",
+            Phase::Convert => "convert",
+        };
+        f.pad(name)
+    }
+}
+
+/// Allocations and elapsed time of a single phase.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PhaseStats {
+    pub phase: Phase,
+    pub elapsed: Duration,
+    /// Number of allocations, including reallocations.
+    pub allocations: usize,
+    /// Sum of the sizes of all allocations.
+    pub bytes: usize,
+    /// Highest number of bytes live at once, relative to the phase start.
+    pub peak_live: usize,
+}
+
+impl fmt::Display for PhaseStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:<11} {:>6} ms {:>10} allocs {:>12} bytes {:>12} peak",
+            self.phase,
+            self.elapsed.as_millis(),
+            self.allocations,
+            self.bytes,
+            self.peak_live
+        )
+    }
+}
+
+/// Number of allocations of the calling thread so far, including
+/// reallocations.
+pub fn thread_allocations() -> usize {
+    THREAD_ALLOCATIONS.with(Cell::get)
+}
+
+/// Runs `f` and reports the allocations of all threads meanwhile. The
+/// result is only valid if no other thread allocates or calls [measure] at
+/// the same time.
+pub fn measure<T>(phase: Phase, f: impl FnOnce() -> T) -> (T, PhaseStats) {
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    let bytes = BYTES.load(Ordering::Relaxed);
+    let live = LIVE.load(Ordering::Relaxed);
+    PEAK.store(live, Ordering::Relaxed);
+
+    let start_time = Instant::now();
+    let result = f();
+    let elapsed = start_time.elapsed();
+
+    let stats = PhaseStats {
+        phase,
+        elapsed,
+        allocations: ALLOCATIONS.load(Ordering::Relaxed) - allocations,
+        bytes: BYTES.load(Ordering::Relaxed) - bytes,
+        peak_live: PEAK.load(Ordering::Relaxed).saturating_sub(live),
+    };
+    (result, stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{measure, thread_allocations, Phase, MEASURE_LOCK};
+    use std::sync::{MutexGuard, PoisonError};
+
+    fn lock() -> MutexGuard<'static, ()> {
+        MEASURE_LOCK.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    #[test]
+    fn counts_allocations() {
+        let _lock = lock();
+        let (v, stats) = measure(Phase::Fill, || {
+            let mut v: Vec<Vec<u8>> = Vec::with_capacity(4);
+            for _ in 0..4 {
+                v.push(vec![0u8; 1024]);
+            }
+            v
+        });
+        // Tests running concurrently may add to the counts.
+        assert!(stats.allocations >= 5);
+        assert!(stats.bytes >= 4 * 1024 + 4 * std::mem::size_of::<Vec<u8>>());
+        assert!(stats.peak_live >= 4 * 1024);
+        drop(v);
+    }
+
+    #[test]
+    fn counts_other_threads() {
+        let _lock = lock();
+        let ((), stats) = measure(Phase::Serialize, || {
+            std::thread::spawn(|| drop(vec![0u8; 1 << 20])).join().unwrap();
+        });
+        assert!(stats.bytes >= 1 << 20);
+        assert!(stats.peak_live >= 1 << 20);
+    }
+
+    #[test]
+    fn counts_own_thread() {
+        let before = thread_allocations();
+        let v = vec![0u8; 1024];
+        assert_eq!(thread_allocations() - before, 1);
+        drop(v);
+    }
+
+    #[test]
+    fn peak_is_relative_to_phase() {
+        let _lock = lock();
+        let keep = vec![0u8; 64 << 20];
+        let ((), stats) = measure(Phase::Serialize, || {
+            for _ in 0..8 {
+                drop(vec![0u8; 4096]);
+            }
+        });
+        assert!(stats.allocations >= 8);
+        assert!(stats.peak_live >= 4096 && stats.peak_live < keep.len());
+        drop(keep);
+    }
+}
@@ -0,0 +1,130 @@
+//! This module describes the layout of the rkyv archives written by this
+//! build.
+//!
+//! rkyv fixes the layout at compile time. By default archives use the
+//! endianness of the writing host and 32-bit relative pointers. As pointers
+//! are signed, this limits archives to 2 GiB, which the codec checks before
+//! serializing a leaf. The cargo features of this crate forward to rkyv:
+//!
+//! - `archive_le`/`archive_be` write little/big endian archives on any host,
+//! - `size_64` writes 64-bit relative pointers and lengths and has to replace
+//!   the default `size_32`, i.e. requires `--no-default-features`.
+//!
+//! Archived integers have to be read with [rkyv::from_archived] to compile
+//! under every combination. Bincode always writes little endian integers and
+//! 64-bit lengths and is not affected.
+
+use crate::error::{Error, Result};
+
+use std::fmt;
+
+/// Byte order of archived integers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Endianness {
+    Little,
+    Big,
+}
+
+/// The layout of rkyv archives.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ArchiveFormat {
+    pub endianness: Endianness,
+    /// Whether the endianness was chosen explicitly rather than following the
+    /// host.
+    pub explicit: bool,
+    /// Width of relative pointers and archived lengths in bits.
+    pub pointer_bits: u32,
+}
+
+impl ArchiveFormat {
+    /// The format written and read by this build.
+    pub const CURRENT: Self = ArchiveFormat {
+        endianness: if cfg!(feature = "archive_be") || (cfg!(target_endian = "big") && !cfg!(feature = "archive_le")) {
+            Endianness::Big
+        } else {
+            Endianness::Little
+        },
+        explicit: cfg!(any(feature = "archive_le", feature = "archive_be")),
+        pointer_bits: if cfg!(feature = "size_64") { 64 } else { 32 },
+    };
+
+    /// The format of the rkyv fixtures committed to `fixtures/`.
+    pub const FIXTURES: Self = ArchiveFormat { endianness: Endianness::Little, explicit: true, pointer_bits: 32 };
+
+    /// Returns whether archives written in `other` have the same bytes as
+    /// archives written in this format.
+    pub fn reads(&self, other: &Self) -> bool {
+        self.endianness == other.endianness && self.pointer_bits == other.pointer_bits
+    }
+
+    /// Length of the largest archive whose relative pointers cannot
+    /// overflow.
+    pub fn max_archive_len(&self) -> u64 {
+        1 << (self.pointer_bits - 1)
+    }
+
+    /// Returns [Error::ArchiveSize] unless archives of up to `size` bytes
+    /// can be written in this format.
+    pub fn check_size(&self, size: usize) -> Result<()> {
+        if size as u64 > self.max_archive_len() {
+            return Err(Error::ArchiveSize { size, format: *self });
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for ArchiveFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let endianness = match self.endianness {
+            Endianness::Little => "le",
+            Endianness::Big => "be",
+        };
+        let host = if self.explicit { "" } else { " (host)" };
+        f.pad(&format!("{}/{}-bit{}", endianness, self.pointer_bits, host))
+    }
+}
+
+/// Returns whether this build reads the committed rkyv fixtures. Tests on
+/// fixtures are ignored by builds with another format.
+pub fn reads_fixtures() -> bool {
+    ArchiveFormat::CURRENT.reads(&ArchiveFormat::FIXTURES)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::mem;
+
+    #[test]
+    fn current_format_matches_layout() {
+        let format = ArchiveFormat::CURRENT;
+        let bytes = rkyv::to_bytes::<_, 8>(&0x0102_0304u32).unwrap();
+        let expected = match format.endianness {
+            Endianness::Little => 0x0102_0304u32.to_le_bytes(),
+            Endianness::Big => 0x0102_0304u32.to_be_bytes(),
+        };
+        assert_eq!(bytes[..], expected);
+        // A pointer and a length.
+        assert_eq!(mem::size_of::<rkyv::vec::ArchivedVec<u8>>() * 8, 2 * format.pointer_bits as usize);
+    }
+
+    #[test]
+    fn describes_format() {
+        assert_eq!(ArchiveFormat::FIXTURES.to_string(), "le/32-bit");
+        let host = ArchiveFormat { explicit: false, ..ArchiveFormat::FIXTURES };
+        assert_eq!(host.to_string(), "le/32-bit (host)");
+        assert!(host.reads(&ArchiveFormat::FIXTURES));
+        assert!(!ArchiveFormat { pointer_bits: 64, ..host }.reads(&ArchiveFormat::FIXTURES));
+    }
+
+    #[test]
+    fn oversized_archives_are_rejected() {
+        let format = ArchiveFormat::FIXTURES;
+        assert_eq!(format.max_archive_len(), 1 << 31);
+        assert!(format.check_size(1 << 31).is_ok());
+        let err = format.check_size((1 << 31) + 1).unwrap_err();
+        assert_eq!(err.phase(), "archive size");
+        assert!(err.to_string().contains("le/32-bit"), "{}", err);
+        assert!(ArchiveFormat { pointer_bits: 64, ..format }.check_size(5 << 30).is_ok());
+    }
+}
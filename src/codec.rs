@@ -0,0 +1,430 @@
+//! This module provides the encode and decode paths of both codecs.
+//!
+//! rkyv works on `Type_A` and bincode on `Type_B`, exactly as the benchmark
+//! cases do. The `_value` variants accept any other node type. Every function
+//! reports failures as [Error], tagged with the phase in which they happened.
+
+use crate::archive_format::ArchiveFormat;
+use crate::cow_bytes::{ArchivedSlicedCowBytes, CowBytes, SlicedCowBytes};
+pub use crate::error::Codec;
+use crate::error::{Error, Result};
+use crate::{ArchivedType_A, Type_A, Type_B};
+
+use std::{collections::BTreeMap, mem};
+
+use bincode::Options;
+use rkyv::de::deserializers::SharedDeserializeMap;
+use rkyv::ser::serializers::{
+    AlignedSerializer, AllocScratch, AllocSerializer, BufferSerializer, BufferSerializerError, CompositeSerializer,
+    CompositeSerializerError, FallbackScratch, HeapScratch, SharedSerializeMap,
+};
+use rkyv::ser::Serializer;
+use rkyv::validation::validators::DefaultValidator;
+use rkyv::{AlignedVec, Archive, CheckBytes, Deserialize};
+use serde::de::DeserializeOwned;
+
+/// Serializes `data` into a fresh buffer.
+///
+/// Leaves which might not be addressable in the [ArchiveFormat] of this
+/// build are rejected up front, as rkyv panics once a relative pointer
+/// overflows.
+pub fn rkyv_serialize(data: &Type_A) -> Result<AlignedVec> {
+    ArchiveFormat::CURRENT.check_size(data.rkyv_size())?;
+    rkyv_serialize_value(data)
+}
+
+/// Serializes any archivable `data` into a fresh buffer.
+pub fn rkyv_serialize_value<T: rkyv::Serialize<AllocSerializer<0>>>(data: &T) -> Result<AlignedVec> {
+    let mut serializer = AllocSerializer::<0>::default();
+    serializer.serialize_value(data).map_err(Error::serialize(Codec::Rkyv))?;
+    Ok(serializer.into_serializer().into_inner())
+}
+
+/// Alignment required of buffers passed to [rkyv_serialize_into], matching
+/// the alignment of [AlignedVec].
+pub const RKYV_BUFFER_ALIGNMENT: usize = AlignedVec::ALIGNMENT;
+
+/// Serializes nodes with rkyv, reusing the output buffer and the scratch
+/// space between writes.
+///
+/// The first `N` bytes of scratch space are allocated once up front, larger
+/// requests fall back to [AllocScratch]. [rkyv_serialize] corresponds to a
+/// fresh `RkyvWriter<0>` per node.
+pub struct RkyvWriter<const N: usize> {
+    buffer: AlignedVec,
+    scratch: Option<FallbackScratch<HeapScratch<N>, AllocScratch>>,
+}
+
+impl<const N: usize> RkyvWriter<N> {
+    /// Creates a writer with an empty output buffer.
+    pub fn new() -> Self {
+        Self::with_capacity(0)
+    }
+
+    /// Creates a writer whose output buffer can hold `capacity` bytes
+    /// before it has to grow.
+    pub fn with_capacity(capacity: usize) -> Self {
+        RkyvWriter {
+            buffer: AlignedVec::with_capacity(capacity),
+            scratch: Some(FallbackScratch::default()),
+        }
+    }
+
+    /// Serializes `data` and returns the encoded bytes, which stay valid
+    /// until the next write.
+    pub fn write(&mut self, data: &Type_A) -> Result<&AlignedVec> {
+        ArchiveFormat::CURRENT.check_size(data.rkyv_size())?;
+        self.buffer.clear();
+        // Type_A holds no shared pointers, so the map stays empty and does
+        // not allocate.
+        let mut serializer = CompositeSerializer::new(
+            AlignedSerializer::new(&mut self.buffer),
+            self.scratch.take().unwrap_or_default(),
+            SharedSerializeMap::new(),
+        );
+        let result = serializer.serialize_value(data);
+        let (_, scratch, _) = serializer.into_components();
+        // A failed write may leave scratch allocations behind, start over.
+        self.scratch = result.is_ok().then_some(scratch);
+        result.map_err(Error::serialize(Codec::Rkyv))?;
+        Ok(&self.buffer)
+    }
+
+    /// Serializes `data` into the start of `out` and returns the number of
+    /// bytes written. The archived root ends at that position, so
+    /// `&out[..written]` can be passed to [rkyv_check].
+    ///
+    /// `out` must be aligned to [RKYV_BUFFER_ALIGNMENT]. If the node does not
+    /// fit, [Error::SizeLimit] is returned and the contents of `out` are
+    /// unspecified.
+    pub fn write_into(&mut self, data: &Type_A, out: &mut [u8]) -> Result<usize> {
+        if !(out.as_ptr() as usize).is_multiple_of(RKYV_BUFFER_ALIGNMENT) {
+            return Err(Error::Envelope {
+                codec: Codec::Rkyv,
+                reason: format!("output buffer is not aligned to {} bytes", RKYV_BUFFER_ALIGNMENT),
+            });
+        }
+        ArchiveFormat::CURRENT.check_size(data.rkyv_size())?;
+        let limit = out.len();
+        let mut serializer = CompositeSerializer::new(
+            BufferSerializer::new(out),
+            self.scratch.take().unwrap_or_default(),
+            SharedSerializeMap::new(),
+        );
+        let result = serializer.serialize_value(data);
+        let (buffer, scratch, _) = serializer.into_components();
+        self.scratch = result.is_ok().then_some(scratch);
+        match result {
+            Ok(_) => Ok(buffer.pos()),
+            Err(CompositeSerializerError::SerializerError(BufferSerializerError::Overflow { .. })) => {
+                Err(Error::SizeLimit { codec: Codec::Rkyv, size: None, limit })
+            }
+            Err(e) => Err(Error::Serialize { codec: Codec::Rkyv, message: e.to_string() }),
+        }
+    }
+}
+
+impl<const N: usize> Default for RkyvWriter<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Serializes `data` into the start of `out`, see [RkyvWriter::write_into].
+pub fn rkyv_serialize_into(data: &Type_A, out: &mut [u8]) -> Result<usize> {
+    RkyvWriter::<0>::new().write_into(data, out)
+}
+
+/// Checks that `bytes` can hold an archived root of type `R` at all.
+fn rkyv_envelope<R>(bytes: &[u8]) -> Result<()> {
+    if bytes.len() < mem::size_of::<R>() {
+        return Err(Error::Envelope {
+            codec: Codec::Rkyv,
+            reason: format!("{} bytes are too short for the root", bytes.len()),
+        });
+    }
+    if !(bytes.as_ptr() as usize).is_multiple_of(mem::align_of::<R>()) {
+        return Err(Error::Envelope {
+            codec: Codec::Rkyv,
+            reason: format!("buffer is not aligned to {} bytes", mem::align_of::<R>()),
+        });
+    }
+    Ok(())
+}
+
+/// Validates `bytes` and returns the archived root.
+///
+/// In addition to `check_archived_root` this verifies the bounds of every
+/// `SlicedCowBytes`, which bytecheck does not cover.
+pub fn rkyv_check(bytes: &[u8]) -> Result<&ArchivedType_A> {
+    let archived = rkyv_check_value::<Type_A>(bytes)?;
+    check_slices(archived.entries.iter().map(|entry| (entry.key.as_ref(), &entry.value.1)))?;
+    Ok(archived)
+}
+
+/// Checks that every archived value slice lies within its backing buffer,
+/// which bytecheck does not cover. `entries` yields the key and value of
+/// each entry, the key only names the entry in the error.
+pub fn check_slices<'a>(mut entries: impl Iterator<Item = (&'a [u8], &'a ArchivedSlicedCowBytes)>) -> Result<()> {
+    match entries.find(|(_, value)| !value.is_in_bounds()) {
+        Some((key, _)) => Err(Error::Validate { context: format!("slice of entry {:?} is out of bounds", key) }),
+        None => Ok(()),
+    }
+}
+
+/// Validates `bytes` with `check_archived_root` and returns the archived
+/// root of type `T`.
+pub fn rkyv_check_value<'a, T: Archive>(bytes: &'a [u8]) -> Result<&'a T::Archived>
+where
+    T::Archived: CheckBytes<DefaultValidator<'a>>,
+{
+    rkyv_envelope::<T::Archived>(bytes)?;
+    rkyv::check_archived_root::<T>(bytes).map_err(Error::validate)
+}
+
+/// Returns the archived root without any validation.
+///
+/// # Safety
+///
+/// `bytes` must contain an archive written by [rkyv_serialize].
+pub unsafe fn rkyv_access(bytes: &[u8]) -> Result<&ArchivedType_A> {
+    rkyv_envelope::<ArchivedType_A>(bytes)?;
+    Ok(rkyv::archived_root::<Type_A>(bytes))
+}
+
+/// Deserializes an archived node.
+pub fn rkyv_deserialize(archived: &ArchivedType_A) -> Result<Type_A> {
+    rkyv_deserialize_value(archived)
+}
+
+/// Deserializes any archived value of type `T`.
+pub fn rkyv_deserialize_value<T: Archive>(archived: &T::Archived) -> Result<T>
+where
+    T::Archived: Deserialize<T, SharedDeserializeMap>,
+{
+    archived.deserialize(&mut SharedDeserializeMap::new()).map_err(Error::deserialize(Codec::Rkyv))
+}
+
+/// Copies an archived node into a `Type_B`, entry by entry. Sliced values
+/// are copied as their slice only, which has to be in bounds.
+pub fn rkyv_convert(archived: &ArchivedType_A) -> Result<Type_B> {
+    let mut entries = BTreeMap::new();
+    for entry in archived.entries.iter() {
+        let value = entry.value.1.bytes().ok_or_else(|| Error::Deserialize {
+            codec: Codec::Rkyv,
+            message: format!("slice of entry {:?} is out of bounds", entry.key.as_ref()),
+        })?;
+        entries.insert(CowBytes::from(&entry.key), ((&entry.value.0).into(), SlicedCowBytes::from(CowBytes::from(value))));
+    }
+    Ok(Type_B { entries })
+}
+
+/// Serializes `data` into a fresh buffer.
+pub fn bincode_serialize(data: &Type_B) -> Result<Vec<u8>> {
+    bincode_serialize_value(data)
+}
+
+/// Serializes any serde-serializable `data` into a fresh buffer.
+pub fn bincode_serialize_value<T: serde::Serialize>(data: &T) -> Result<Vec<u8>> {
+    let mut buf: Vec<u8> = Vec::new();
+    bincode::serialize_into(&mut buf, data).map_err(Error::serialize(Codec::Bincode))?;
+    Ok(buf)
+}
+
+/// Serializes `data` into the start of `out` and returns the number of
+/// bytes written.
+///
+/// The encoded size is computed up front, so nothing is written if the node
+/// does not fit into `out`.
+pub fn bincode_serialize_into(data: &Type_B, out: &mut [u8]) -> Result<usize> {
+    let size = bincode::serialized_size(data).map_err(Error::serialize(Codec::Bincode))? as usize;
+    if size > out.len() {
+        return Err(Error::SizeLimit { codec: Codec::Bincode, size: Some(size), limit: out.len() });
+    }
+    let mut writer = &mut out[..];
+    bincode::serialize_into(&mut writer, data).map_err(Error::serialize(Codec::Bincode))?;
+    Ok(size)
+}
+
+/// Serializes nodes with bincode, reusing the output buffer between writes.
+#[derive(Debug, Default)]
+pub struct BincodeWriter {
+    buffer: Vec<u8>,
+}
+
+impl BincodeWriter {
+    /// Creates a writer whose output buffer can hold `capacity` bytes
+    /// before it has to grow.
+    pub fn with_capacity(capacity: usize) -> Self {
+        BincodeWriter { buffer: Vec::with_capacity(capacity) }
+    }
+
+    /// Serializes `data` and returns the encoded bytes, which stay valid
+    /// until the next write.
+    pub fn write(&mut self, data: &Type_B) -> Result<&[u8]> {
+        self.buffer.clear();
+        bincode::serialize_into(&mut self.buffer, data).map_err(Error::serialize(Codec::Bincode))?;
+        Ok(&self.buffer)
+    }
+}
+
+/// Deserializes a node.
+///
+/// The options match `bincode::deserialize`, except for a size limit of
+/// `bytes.len()`. It keeps a corrupted length prefix from requesting more
+/// memory than the buffer could possibly describe. Hitting the limit is
+/// reported as [Error::SizeLimit], as by `stream::bincode_read`.
+pub fn bincode_deserialize(bytes: &[u8]) -> Result<Type_B> {
+    bincode_deserialize_value(bytes)
+}
+
+/// Deserializes any serde-deserializable value of type `T`, with the same
+/// options as [bincode_deserialize].
+pub fn bincode_deserialize_value<T: DeserializeOwned>(bytes: &[u8]) -> Result<T> {
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+        .with_limit(bytes.len() as u64)
+        .deserialize::<T>(bytes)
+        .map_err(|e| Error::bincode_deserialize(*e, bytes.len() as u64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::corruption;
+    use crate::test_support::sample_entries;
+    use crate::key_info::KeyInfo;
+
+    #[test]
+    fn round_trip() {
+        let data_a = Type_A { entries: sample_entries(16, 32) };
+        let bytes = rkyv_serialize(&data_a).unwrap();
+        let archived = rkyv_check(&bytes).unwrap();
+        assert_eq!(rkyv_deserialize(archived).unwrap(), data_a);
+        assert_eq!(rkyv_convert(archived).unwrap().entries, data_a.entries);
+
+        let data_b = Type_B { entries: sample_entries(16, 32) };
+        let bytes = bincode_serialize(&data_b).unwrap();
+        assert_eq!(bincode_deserialize(&bytes).unwrap().entries, data_b.entries);
+    }
+
+    #[test]
+    fn convert_copies_slices_only() {
+        let mut entries = sample_entries(4, 8);
+        let sliced = CowBytes::from(&b"0123456789"[..]).slice(3, 4);
+        entries.insert(CowBytes::from(&b"sliced"[..]), (KeyInfo::default(), sliced));
+        let data_a = Type_A { entries };
+        let bytes = rkyv_serialize(&data_a).unwrap();
+        let converted = rkyv_convert(rkyv_check(&bytes).unwrap()).unwrap();
+        assert_eq!(&converted.entries[&b"sliced"[..]].1[..], b"3456");
+        assert_eq!(converted.entries, data_a.entries);
+
+        // Slices out of bounds pass bytecheck, but not the conversion.
+        let mut out_of_bounds = 0;
+        for mutation in corruption::exhaustive_mutations(bytes.len()) {
+            let mut mutated = AlignedVec::new();
+            mutated.extend_from_slice(&mutation.apply(&bytes));
+            if let (Ok(archived), Err(_)) = (rkyv_check_value::<Type_A>(&mutated), rkyv_check(&mutated)) {
+                assert_eq!(rkyv_convert(archived).unwrap_err().phase(), "deserialize");
+                out_of_bounds += 1;
+            }
+        }
+        assert!(out_of_bounds > 0);
+    }
+
+    #[test]
+    fn writers_match_fresh_serialization() {
+        let data_a = Type_A { entries: sample_entries(16, 32) };
+        let data_b = Type_B { entries: sample_entries(16, 32) };
+
+        let mut rkyv_writer = RkyvWriter::<256>::new();
+        let mut bincode_writer = BincodeWriter::default();
+        for _ in 0..3 {
+            assert_eq!(&rkyv_writer.write(&data_a).unwrap()[..], &rkyv_serialize(&data_a).unwrap()[..]);
+            assert_eq!(bincode_writer.write(&data_b).unwrap(), &bincode_serialize(&data_b).unwrap()[..]);
+        }
+    }
+
+    #[test]
+    fn writers_stop_allocating() {
+        use crate::allocation::thread_allocations;
+
+        let data_a = Type_A { entries: sample_entries(16, 32) };
+        let data_b = Type_B { entries: sample_entries(16, 32) };
+        let mut rkyv_writer = RkyvWriter::<4096>::new();
+        let mut bincode_writer = BincodeWriter::default();
+        rkyv_writer.write(&data_a).unwrap();
+        bincode_writer.write(&data_b).unwrap();
+
+        // Writers run on this thread, so other tests cannot add to the count.
+        let allocations = thread_allocations();
+        rkyv_writer.write(&data_a).unwrap();
+        assert_eq!(thread_allocations(), allocations);
+        bincode_writer.write(&data_b).unwrap();
+        assert_eq!(thread_allocations(), allocations);
+    }
+
+    #[test]
+    fn serialize_into_caller_buffer() {
+        let data_a = Type_A { entries: sample_entries(16, 32) };
+        let data_b = Type_B { entries: sample_entries(16, 32) };
+        let expected_a = rkyv_serialize(&data_a).unwrap();
+        let expected_b = bincode_serialize(&data_b).unwrap();
+
+        let mut block = AlignedVec::new();
+        block.resize(4096, 0);
+        let written = rkyv_serialize_into(&data_a, &mut block).unwrap();
+        assert_eq!(&block[..written], &expected_a[..]);
+        assert_eq!(rkyv_deserialize(rkyv_check(&block[..written]).unwrap()).unwrap(), data_a);
+
+        let written = bincode_serialize_into(&data_b, &mut block).unwrap();
+        assert_eq!(&block[..written], &expected_b[..]);
+    }
+
+    #[test]
+    fn serialize_into_too_small_buffer() {
+        let data_a = Type_A { entries: sample_entries(16, 32) };
+        let data_b = Type_B { entries: sample_entries(16, 32) };
+        let mut block = AlignedVec::new();
+        block.resize(256, 0);
+
+        let err = rkyv_serialize_into(&data_a, &mut block).err().unwrap();
+        assert!(matches!(err, Error::SizeLimit { codec: Codec::Rkyv, limit: 256, .. }));
+        let err = rkyv_serialize_into(&data_a, &mut block[1..]).err().unwrap();
+        assert_eq!(err.phase(), "envelope");
+
+        let size = bincode_serialize(&data_b).unwrap().len();
+        let err = bincode_serialize_into(&data_b, &mut block).err().unwrap();
+        assert!(matches!(err, Error::SizeLimit { codec: Codec::Bincode, size: Some(s), limit: 256 } if s == size));
+
+        // The writer keeps working after an overflow.
+        let mut writer = RkyvWriter::<1024>::new();
+        assert!(writer.write_into(&data_a, &mut block).is_err());
+        block.resize(4096, 0);
+        assert!(writer.write_into(&data_a, &mut block).is_ok());
+    }
+
+    #[test]
+    fn size_bound_covers_archive() {
+        // The bound is checked before anything is written.
+        let data_a = Type_A { entries: sample_entries(16, 4096) };
+        assert!(rkyv_serialize(&data_a).unwrap().len() <= data_a.rkyv_size());
+        let small = ArchiveFormat { pointer_bits: 16, ..ArchiveFormat::FIXTURES };
+        assert_eq!(small.check_size(data_a.rkyv_size()).unwrap_err().phase(), "archive size");
+    }
+
+    #[test]
+    fn phases_are_reported() {
+        let bytes = rkyv_serialize(&Type_A { entries: sample_entries(16, 32) }).unwrap();
+        assert_eq!(rkyv_check(&bytes[..4]).err().unwrap().phase(), "envelope");
+        assert_eq!(rkyv_check(&bytes[1..]).err().unwrap().phase(), "envelope");
+
+        let mut truncated = AlignedVec::new();
+        truncated.extend_from_slice(&bytes[..bytes.len() - 8]);
+        assert_eq!(rkyv_check(&truncated).err().unwrap().phase(), "validate");
+
+        let bytes = bincode_serialize(&Type_B { entries: sample_entries(16, 32) }).unwrap();
+        assert_eq!(bincode_deserialize(&bytes[..bytes.len() - 1]).err().unwrap().phase(), "deserialize");
+    }
+}
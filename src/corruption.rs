@@ -0,0 +1,256 @@
+//! This module provides deterministic mutations of serialized nodes, used to
+//! check that corrupted archives are reported as errors instead of panicking.
+//!
+//! Every mutated buffer is fed to the checked rkyv path
+//! (`check_archived_root`) and to `bincode`. The unchecked path
+//! (`archived_root`) is never run on mutated bytes, as that would be undefined
+//! behaviour. It performs no checks at all, so it would have accepted every
+//! input listed here, with the following consequences:
+//!
+//! - A flipped bit in a relative pointer or a length makes it read outside of
+//!   the buffer.
+//! - A truncated buffer moves the root, which is located relative to the end
+//!   of the buffer, so the root is read from the middle of some other object.
+//! - An extended buffer does the same, reading the root from the appended
+//!   bytes.
+//! - A flipped bit in `pos`/`len` of a `SlicedCowBytes` makes the deserialized
+//!   slice panic on first access. `check_bytes` does not catch this either, see
+//!   [crate::cow_bytes::ArchivedSlicedCowBytes::is_in_bounds].
+//!
+//! A flipped bit inside key or value bytes is structurally valid for both
+//! codecs and is accepted silently, it is reported as [Outcome::Altered].
+//!
+//! [decode_any_rkyv_type_a] and [decode_any_bincode_type_b] decode arbitrary
+//! bytes and must never panic. The tests feed them seeded pseudo-random
+//! mutations, there is no fuzzer behind them: this package only builds
+//! binaries, which a cargo-fuzz crate cannot link against. The two functions
+//! have the signature of a fuzz target body, so once the node types move into
+//! a library target they can be called from `fuzz_target!` unchanged.
+
+use crate::{ArchivedType_A, Type_A, Type_B};
+
+use crate::codec;
+
+use rkyv::AlignedVec;
+
+/// A single deterministic change to a serialized buffer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mutation {
+    /// Flips bit `bit` of the byte at `offset`.
+    FlipBit { offset: usize, bit: u8 },
+    /// Cuts the buffer down to `len` bytes.
+    Truncate { len: usize },
+    /// Appends `count` copies of `fill` to the buffer.
+    Extend { count: usize, fill: u8 },
+}
+
+impl Mutation {
+    /// Applies the mutation to a copy of `bytes`.
+    pub fn apply(&self, bytes: &[u8]) -> Vec<u8> {
+        let mut out = bytes.to_vec();
+        match *self {
+            Mutation::FlipBit { offset, bit } => out[offset] ^= 1 << bit,
+            Mutation::Truncate { len } => out.truncate(len),
+            Mutation::Extend { count, fill } => out.extend(std::iter::repeat_n(fill, count)),
+        }
+        out
+    }
+}
+
+/// What a decoder made of a mutated buffer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    /// The decoder returned an error.
+    Rejected,
+    /// Validation passed, but a `SlicedCowBytes` points outside of its data.
+    OutOfBounds,
+    /// The buffer decoded to the original node.
+    Unchanged,
+    /// The buffer decoded to a different node without any error.
+    Altered,
+}
+
+/// Number of outcomes per decoder over a set of mutations.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Report {
+    pub rejected: usize,
+    pub out_of_bounds: usize,
+    pub unchanged: usize,
+    pub altered: usize,
+}
+
+impl Report {
+    fn record(&mut self, outcome: Outcome) {
+        match outcome {
+            Outcome::Rejected => self.rejected += 1,
+            Outcome::OutOfBounds => self.out_of_bounds += 1,
+            Outcome::Unchanged => self.unchanged += 1,
+            Outcome::Altered => self.altered += 1,
+        }
+    }
+
+    /// Total number of mutations recorded.
+    pub fn total(&self) -> usize {
+        self.rejected + self.out_of_bounds + self.unchanged + self.altered
+    }
+}
+
+/// Returns whether all slices of a validated archive stay within their data.
+pub fn slices_in_bounds(archived: &ArchivedType_A) -> bool {
+    archived.entries.iter().all(|entry| entry.value.1.is_in_bounds())
+}
+
+/// Decodes `bytes` through the checked rkyv path and compares the result
+/// with `original`.
+///
+/// Unlike [codec::rkyv_check] this keeps bytecheck failures and slice bounds
+/// violations apart.
+pub fn rkyv_outcome(bytes: &[u8], original: &Type_A) -> Outcome {
+    // `check_archived_root` requires the buffer to be aligned like the root.
+    let mut aligned = AlignedVec::with_capacity(bytes.len());
+    aligned.extend_from_slice(bytes);
+
+    let archived = match rkyv::check_archived_root::<Type_A>(&aligned[..]) {
+        Ok(archived) => archived,
+        Err(_) => return Outcome::Rejected,
+    };
+    if !slices_in_bounds(archived) {
+        return Outcome::OutOfBounds;
+    }
+    match codec::rkyv_deserialize(archived) {
+        Ok(deserialized) if deserialized == *original => Outcome::Unchanged,
+        Ok(_) => Outcome::Altered,
+        Err(_) => Outcome::Rejected,
+    }
+}
+
+/// Decodes `bytes` with bincode and compares the result with `original`.
+pub fn bincode_outcome(bytes: &[u8], original: &Type_B) -> Outcome {
+    match codec::bincode_deserialize(bytes) {
+        Ok(deserialized) if deserialized.entries == original.entries => Outcome::Unchanged,
+        Ok(_) => Outcome::Altered,
+        Err(_) => Outcome::Rejected,
+    }
+}
+
+/// Every single-bit flip, every truncation and a few extensions of a buffer
+/// of length `len`.
+pub fn exhaustive_mutations(len: usize) -> Vec<Mutation> {
+    let flips = (0..len).flat_map(|offset| (0..8).map(move |bit| Mutation::FlipBit { offset, bit }));
+    let truncations = (0..len).map(|len| Mutation::Truncate { len });
+    let extensions = [1, 4, 8, 16].iter().flat_map(|&count| {
+        [0x00, 0xff].iter().map(move |&fill| Mutation::Extend { count, fill })
+    });
+    flips.chain(truncations).chain(extensions).collect()
+}
+
+/// Applies `mutations` to the serialized forms of `data_a` and of the
+/// `Type_B` with the same entries, and reports the outcomes for rkyv and
+/// bincode, in this order.
+pub fn run(data_a: &Type_A, mutations: impl Fn(usize) -> Vec<Mutation>) -> (Report, Report) {
+    let data_b = Type_B { entries: data_a.entries.clone() };
+
+    let rkyv_buf = codec::rkyv_serialize(data_a).unwrap();
+    let bincode_buf = codec::bincode_serialize(&data_b).unwrap();
+
+    let mut rkyv_report = Report::default();
+    for mutation in mutations(rkyv_buf.len()) {
+        rkyv_report.record(rkyv_outcome(&mutation.apply(&rkyv_buf), data_a));
+    }
+
+    let mut bincode_report = Report::default();
+    for mutation in mutations(bincode_buf.len()) {
+        bincode_report.record(bincode_outcome(&mutation.apply(&bincode_buf), &data_b));
+    }
+
+    (rkyv_report, bincode_report)
+}
+
+/// Decodes arbitrary bytes through the checked rkyv path and touches every
+/// value. Must never panic, whatever the input.
+pub fn decode_any_rkyv_type_a(bytes: &[u8]) {
+    let mut aligned = AlignedVec::with_capacity(bytes.len());
+    aligned.extend_from_slice(bytes);
+    if let Ok(archived) = codec::rkyv_check(&aligned) {
+        let deserialized = codec::rkyv_deserialize(archived).unwrap();
+        for (_, (_, value)) in deserialized.entries.iter() {
+            let _ = value.len();
+        }
+    }
+}
+
+/// Decodes arbitrary bytes with bincode. Must never panic, whatever the
+/// input.
+pub fn decode_any_bincode_type_b(bytes: &[u8]) {
+    let _ = codec::bincode_deserialize(bytes);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{sample_entries, XorShift};
+
+    fn sample_node(count: u32, value_len: usize) -> Type_A {
+        Type_A { entries: sample_entries(count, value_len) }
+    }
+
+    /// `count` pseudo-random mutations of a buffer of length `len`, derived
+    /// from `seed` so that every run checks the same inputs.
+    fn random_mutations(len: usize, count: usize, seed: u64) -> Vec<Mutation> {
+        let mut random = XorShift::new(seed);
+        (0..count)
+            .map(|_| match random.below(4) {
+                0 => Mutation::Truncate { len: random.below(len as u64) as usize },
+                1 => Mutation::Extend { count: 1 + random.below(64) as usize, fill: random.next_u64() as u8 },
+                _ => Mutation::FlipBit { offset: random.below(len as u64) as usize, bit: random.below(8) as u8 },
+            })
+            .collect()
+    }
+
+    #[test]
+    fn unmodified_buffers_decode() {
+        let (rkyv_report, bincode_report) = run(&sample_node(8, 16), |_| vec![Mutation::Extend { count: 0, fill: 0 }]);
+        assert_eq!(rkyv_report.unchanged, 1);
+        assert_eq!(bincode_report.unchanged, 1);
+    }
+
+    #[test]
+    fn truncation_is_rejected() {
+        let truncations = |len| (0..len).map(|len| Mutation::Truncate { len }).collect();
+        let (rkyv_report, bincode_report) = run(&sample_node(8, 16), truncations);
+        assert_eq!(rkyv_report.rejected, rkyv_report.total());
+        assert_eq!(bincode_report.rejected, bincode_report.total());
+    }
+
+    #[test]
+    fn bit_flips_do_not_panic() {
+        let (rkyv_report, bincode_report) = run(&sample_node(8, 16), exhaustive_mutations);
+        assert!(rkyv_report.rejected > 0);
+        assert!(bincode_report.rejected > 0);
+        // Flips inside of value bytes pass both codecs unnoticed.
+        assert!(rkyv_report.altered > 0);
+        assert!(bincode_report.altered > 0);
+    }
+
+    #[test]
+    fn corrupted_slice_bounds_pass_bytecheck() {
+        let (rkyv_report, _) = run(&sample_node(8, 16), exhaustive_mutations);
+        assert!(rkyv_report.out_of_bounds > 0);
+    }
+
+    #[test]
+    fn random_mutations_do_not_panic() {
+        for seed in 1..=4 {
+            run(&sample_node(32, 64), |len| random_mutations(len, 2000, seed));
+        }
+    }
+
+    #[test]
+    fn decoders_accept_garbage() {
+        for mutation in random_mutations(256, 500, 7) {
+            let bytes = mutation.apply(&[0xa5; 256]);
+            decode_any_rkyv_type_a(&bytes);
+            decode_any_bincode_type_b(&bytes);
+        }
+    }
+}
@@ -8,6 +8,7 @@ use std::{
     borrow::Borrow,
     cmp,
     ops::{Deref, DerefMut},
+    pin::Pin,
     sync::Arc,
 };
 use std::cmp::Ordering;
@@ -331,6 +332,34 @@ impl SlicedCowBytes {
     }
 }
 
+impl ArchivedSlicedCowBytes {
+    /// Returns whether `pos..pos+len` lies within the archived data.
+    ///
+    /// `check_bytes` only validates the layout of the individual fields, so an
+    /// archive with corrupted slice bounds passes validation but panics on the
+    /// first access of the deserialized slice.
+    pub fn is_in_bounds(&self) -> bool {
+        self.bytes().is_some()
+    }
+
+    /// Returns the bytes of the slice, or `None` if the slice is out of
+    /// bounds.
+    pub fn bytes(&self) -> Option<&[u8]> {
+        let (pos, len) = (rkyv::from_archived!(self.pos) as usize, rkyv::from_archived!(self.len) as usize);
+        self.data.inner.get(pos..pos.checked_add(len)?)
+    }
+
+    /// Returns the bytes of the slice for overwriting them in place, or
+    /// `None` if the slice is out of bounds.
+    pub fn bytes_mut(self: Pin<&mut Self>) -> Option<&mut [u8]> {
+        let (pos, len) = (rkyv::from_archived!(self.pos) as usize, rkyv::from_archived!(self.len) as usize);
+        // Projecting to the vector does not move it, and neither does
+        // overwriting its bytes.
+        let data = unsafe { self.map_unchecked_mut(|sliced| &mut sliced.data.inner) };
+        data.pin_mut_slice().get_mut().get_mut(pos..pos.checked_add(len)?)
+    }
+}
+
 impl From<CowBytes> for SlicedCowBytes {
     fn from(data: CowBytes) -> Self {
         SlicedCowBytes {
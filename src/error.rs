@@ -0,0 +1,146 @@
+//! This module provides the `Error` type returned by the codec layer.
+//!
+//! The standalone scenario binaries, which encode their own node types
+//! without the codec layer, report their failures with it as well. [Codec]
+//! is defined here for them and re-exported by `codec`.
+
+use crate::archive_format::ArchiveFormat;
+use std::{fmt, io, result};
+
+/// The serialization formats compared by this crate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Codec {
+    Rkyv,
+    Bincode,
+}
+
+impl fmt::Display for Codec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Codec::Rkyv => f.pad("rkyv"),
+            Codec::Bincode => f.pad("bincode"),
+        }
+    }
+}
+
+/// Errors of the encode and decode paths, tagged with the phase in which
+/// they occurred.
+#[derive(Debug)]
+pub enum Error {
+    /// Encoding the node failed.
+    Serialize { codec: Codec, message: String },
+    /// `check_archived_root` rejected the buffer. `context` holds the
+    /// bytecheck error, including the path to the offending field.
+    Validate { context: String },
+    /// Decoding the node, or converting the archived node, failed.
+    Deserialize { codec: Codec, message: String },
+    /// The encoded node does not fit into `limit` bytes.
+    SizeLimit { codec: Codec, size: Option<usize>, limit: usize },
+    /// The rkyv archive of the node may take up to `size` bytes, more than
+    /// the relative pointers of `format` can address.
+    ArchiveSize { size: usize, format: ArchiveFormat },
+    /// The buffer surrounding the node is unusable, e.g. it is too short to
+    /// hold a root or is not aligned for the archived type.
+    Envelope { codec: Codec, reason: String },
+    /// The archive is tagged with a format version this build cannot read.
+    Version { version: u32 },
+    /// Updating an archived node in place failed, e.g. because the key is
+    /// missing or the new value has a different length.
+    InPlace { reason: String },
+    /// An upsert writing `len` bytes at `offset` would grow the value beyond
+    /// `limit` bytes.
+    Upsert { offset: u32, len: usize, limit: usize },
+    /// A configuration file is malformed or describes an invalid setup.
+    Config { message: String },
+    /// Reading or writing the file or pipe holding the node failed.
+    Io(io::Error),
+}
+
+/// Result type of the codec layer.
+pub type Result<T> = result::Result<T, Error>;
+
+impl Error {
+    /// Returns a mapping of encoding failures of `codec`, for `map_err`.
+    pub fn serialize<E: ToString>(codec: Codec) -> impl FnOnce(E) -> Error {
+        move |e| Error::Serialize { codec, message: e.to_string() }
+    }
+
+    /// Maps a bytecheck failure, for `map_err`.
+    pub fn validate(e: impl ToString) -> Error {
+        Error::Validate { context: e.to_string() }
+    }
+
+    /// Returns a mapping of decoding failures of `codec`, for `map_err`.
+    pub fn deserialize<E: ToString>(codec: Codec) -> impl FnOnce(E) -> Error {
+        move |e| Error::Deserialize { codec, message: e.to_string() }
+    }
+
+    /// Maps a failure to decode a bincode node bounded by `limit` bytes.
+    /// Lengths exceeding the bound are reported as [Error::SizeLimit] by the
+    /// in-memory and the streaming decoder alike.
+    pub fn bincode_deserialize(e: bincode::ErrorKind, limit: u64) -> Error {
+        match e {
+            bincode::ErrorKind::SizeLimit => {
+                Error::SizeLimit { codec: Codec::Bincode, size: None, limit: limit as usize }
+            }
+            other => Error::Deserialize { codec: Codec::Bincode, message: other.to_string() },
+        }
+    }
+
+    /// Name of the phase in which the error occurred.
+    pub fn phase(&self) -> &'static str {
+        match self {
+            Error::Serialize { .. } => "serialize",
+            Error::Validate { .. } => "validate",
+            Error::Deserialize { .. } => "deserialize",
+            Error::SizeLimit { .. } => "size limit",
+            Error::ArchiveSize { .. } => "archive size",
+            Error::Envelope { .. } => "envelope",
+            Error::Version { .. } => "version",
+            Error::InPlace { .. } => "in-place update",
+            Error::Upsert { .. } => "apply",
+            Error::Config { .. } => "config",
+            Error::Io(_) => "io",
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Serialize { codec, message } => write!(f, "{} serialization failed: {}", codec, message),
+            Error::Validate { context } => write!(f, "rkyv validation failed: {}", context),
+            Error::Deserialize { codec, message } => write!(f, "{} deserialization failed: {}", codec, message),
+            Error::SizeLimit { codec, size: Some(size), limit } => {
+                write!(f, "{} node of {} bytes exceeds the limit of {} bytes", codec, size, limit)
+            }
+            Error::SizeLimit { codec, size: None, limit } => {
+                write!(f, "{} node exceeds the limit of {} bytes", codec, limit)
+            }
+            Error::ArchiveSize { size, format } => write!(
+                f,
+                "rkyv node of up to {} bytes exceeds the {} bytes addressable by {} archives, see the size_64 feature",
+                size,
+                format.max_archive_len(),
+                format
+            ),
+            Error::Envelope { codec, reason } => write!(f, "malformed {} buffer: {}", codec, reason),
+            Error::Version { version } => write!(f, "unsupported format version {}", version),
+            Error::InPlace { reason } => write!(f, "in-place update failed: {}", reason),
+            Error::Upsert { offset, len, limit } => {
+                write!(f, "upsert of {} bytes at offset {} exceeds the value limit of {} bytes", len, offset, limit)
+            }
+            Error::Config { message } => write!(f, "invalid configuration: {}", message),
+            Error::Io(e) => write!(f, "i/o failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
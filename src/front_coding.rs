@@ -0,0 +1,493 @@
+//! This module provides `FrontCodedKeys`, a prefix-compressed encoding of
+//! sorted keys, and `FrontCodedLeaf`, a leaf which stores its keys that way.
+//!
+//! Every key is stored as the length of the prefix it shares with its
+//! predecessor, followed by the remaining suffix. Every `restart_interval`th
+//! key is stored in full, and the offsets of these restart points are kept in
+//! a trailer, so a lookup binary searches the restart points and decodes at
+//! most one interval. The whole section is a single byte buffer, so it is
+//! used unchanged by both codecs.
+//!
+//! Layout of the section:
+//!
+//! ```text
+//! entry*  restart: u32*  restart_interval: u32  len: u32  restarts: u32
+//! entry = shared: varint  suffix_len: varint  suffix: [u8]
+//! ```
+
+use crate::codec::{self, Codec};
+use crate::cow_bytes::{ArchivedSlicedCowBytes, CowBytes, SlicedCowBytes};
+use crate::error::{Error, Result};
+use crate::key_info::{ArchivedKeyInfo, KeyInfo};
+
+use std::{cmp::Ordering, collections::BTreeMap, convert::TryInto};
+
+/// Restart interval used unless specified otherwise.
+pub const DEFAULT_RESTART_INTERVAL: usize = 16;
+
+// restart_interval, len and the number of restarts.
+const TRAILER_SIZE: usize = 3 * 4;
+
+fn write_varint(out: &mut CowBytes, mut value: usize) {
+    while value >= 0x80 {
+        out.push_slice(&[value as u8 | 0x80]);
+        value >>= 7;
+    }
+    out.push_slice(&[value as u8]);
+}
+
+fn read_varint(bytes: &[u8], offset: &mut usize) -> Option<usize> {
+    let mut value = 0usize;
+    for shift in (0..usize::BITS).step_by(7) {
+        let byte = *bytes.get(*offset)?;
+        *offset += 1;
+        value |= ((byte & 0x7f) as usize).checked_shl(shift)?;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Option<usize> {
+    Some(u32::from_le_bytes(bytes.get(offset..offset + 4)?.try_into().unwrap()) as usize)
+}
+
+/// Sorted keys in front-coded form.
+#[derive(
+    Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize,
+)]
+#[archive(check_bytes)]
+pub struct FrontCodedKeys {
+    data: CowBytes,
+}
+
+impl FrontCodedKeys {
+    /// Encodes `keys`, which have to be sorted in ascending order, storing
+    /// every `restart_interval`th key in full.
+    pub fn build<'a>(keys: impl IntoIterator<Item = &'a [u8]>, restart_interval: usize) -> Self {
+        assert!(restart_interval > 0);
+        let mut data = CowBytes::new();
+        let mut restarts = Vec::new();
+        let mut previous: &[u8] = &[];
+        let mut len = 0;
+        for key in keys {
+            debug_assert!(len == 0 || previous < key, "keys are not sorted");
+            let shared = if len % restart_interval == 0 {
+                restarts.push(data.len() as u32);
+                0
+            } else {
+                previous.iter().zip(key.iter()).take_while(|(a, b)| a == b).count()
+            };
+            write_varint(&mut data, shared);
+            write_varint(&mut data, key.len() - shared);
+            data.push_slice(&key[shared..]);
+            previous = key;
+            len += 1;
+        }
+        for restart in restarts.iter() {
+            data.push_slice(&restart.to_le_bytes());
+        }
+        for field in [restart_interval, len, restarts.len()] {
+            data.push_slice(&(field as u32).to_le_bytes());
+        }
+        FrontCodedKeys { data }
+    }
+
+    /// Returns a view for decoding the keys, or `None` if the trailer of the
+    /// section is malformed, e.g. after decoding corrupted bytes.
+    pub fn view(&self) -> Option<FrontCodedView<'_>> {
+        FrontCodedView::new(&self.data)
+    }
+
+    /// Size of the encoded section in bytes.
+    pub fn encoded_len(&self) -> usize {
+        self.data.len()
+    }
+}
+
+impl ArchivedFrontCodedKeys {
+    /// Returns a view for decoding the keys, or `None` if the trailer of the
+    /// section is malformed.
+    pub fn view(&self) -> Option<FrontCodedView<'_>> {
+        FrontCodedView::new(self.data.as_ref())
+    }
+}
+
+/// Decodes a front-coded key section. Malformed entries end iteration and
+/// fail lookups instead of panicking.
+#[derive(Debug, Clone, Copy)]
+pub struct FrontCodedView<'a> {
+    entries: &'a [u8],
+    restarts: &'a [u8],
+    restart_interval: usize,
+    len: usize,
+}
+
+impl<'a> FrontCodedView<'a> {
+    /// Parses the trailer of the section in `bytes`.
+    pub fn new(bytes: &'a [u8]) -> Option<Self> {
+        let trailer = bytes.len().checked_sub(TRAILER_SIZE)?;
+        let restart_interval = read_u32(bytes, trailer)?;
+        let len = read_u32(bytes, trailer + 4)?;
+        let restarts = read_u32(bytes, trailer + 8)?;
+        if restart_interval == 0 || restarts != len.div_ceil(restart_interval) {
+            return None;
+        }
+        let entries_end = trailer.checked_sub(restarts * 4)?;
+        Some(FrontCodedView {
+            entries: &bytes[..entries_end],
+            restarts: &bytes[entries_end..trailer],
+            restart_interval,
+            len,
+        })
+    }
+
+    /// Number of keys.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns whether there are no keys.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn restart(&self, idx: usize) -> Option<usize> {
+        read_u32(self.restarts, idx * 4)
+    }
+
+    /// Decodes the entry at `offset` on top of its predecessor `key` and
+    /// returns the offset of the next entry.
+    fn decode(&self, offset: usize, key: &mut Vec<u8>) -> Option<usize> {
+        let mut offset = offset;
+        let shared = read_varint(self.entries, &mut offset)?;
+        let suffix_len = read_varint(self.entries, &mut offset)?;
+        let suffix = self.entries.get(offset..offset.checked_add(suffix_len)?)?;
+        if shared > key.len() {
+            return None;
+        }
+        key.truncate(shared);
+        key.extend_from_slice(suffix);
+        Some(offset + suffix_len)
+    }
+
+    /// Returns the full key stored at restart point `idx` without copying.
+    fn restart_key(&self, idx: usize) -> Option<&'a [u8]> {
+        let mut offset = self.restart(idx)?;
+        if read_varint(self.entries, &mut offset)? != 0 {
+            return None;
+        }
+        let len = read_varint(self.entries, &mut offset)?;
+        self.entries.get(offset..offset.checked_add(len)?)
+    }
+
+    /// Returns the position of `key`, or `None` if it is not present.
+    pub fn find(&self, key: &[u8]) -> Option<usize> {
+        // Find the last restart point whose key is not larger than `key`.
+        let (mut low, mut high) = (0, self.restarts.len() / 4);
+        while low < high {
+            let mid = low + (high - low) / 2;
+            match self.restart_key(mid)?.cmp(key) {
+                Ordering::Greater => high = mid,
+                _ => low = mid + 1,
+            }
+        }
+        let restart = low.checked_sub(1)?;
+
+        let mut current = Vec::new();
+        let mut offset = self.restart(restart)?;
+        let first = restart * self.restart_interval;
+        for position in first..self.len.min(first + self.restart_interval) {
+            offset = self.decode(offset, &mut current)?;
+            match current[..].cmp(key) {
+                Ordering::Less => continue,
+                Ordering::Equal => return Some(position),
+                Ordering::Greater => return None,
+            }
+        }
+        None
+    }
+
+    /// Returns the key at `position`.
+    pub fn get(&self, position: usize) -> Option<CowBytes> {
+        if position >= self.len {
+            return None;
+        }
+        let restart = position / self.restart_interval;
+        let mut current = Vec::new();
+        let mut offset = self.restart(restart)?;
+        for _ in restart * self.restart_interval..=position {
+            offset = self.decode(offset, &mut current)?;
+        }
+        Some(CowBytes::from(current))
+    }
+
+    /// Returns whether every entry decodes, every restart point holds a full
+    /// key at the offset of its entry and the entries fill the section.
+    pub fn is_valid(&self) -> bool {
+        let mut current = Vec::new();
+        let mut offset = 0;
+        for position in 0..self.len {
+            if position % self.restart_interval == 0 {
+                if self.restart(position / self.restart_interval) != Some(offset) {
+                    return false;
+                }
+                // A restart point shares no prefix, which `decode` rejects
+                // for an empty predecessor.
+                current.clear();
+            }
+            match self.decode(offset, &mut current) {
+                Some(next) => offset = next,
+                None => return false,
+            }
+        }
+        offset == self.entries.len()
+    }
+
+    /// Returns the keys in order.
+    pub fn iter(&self) -> Iter<'a> {
+        Iter { view: *self, offset: 0, remaining: self.len, current: Vec::new() }
+    }
+}
+
+/// Iterator over the keys of a [FrontCodedView].
+pub struct Iter<'a> {
+    view: FrontCodedView<'a>,
+    offset: usize,
+    remaining: usize,
+    current: Vec<u8>,
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = CowBytes;
+
+    fn next(&mut self) -> Option<CowBytes> {
+        if self.remaining == 0 {
+            return None;
+        }
+        match self.view.decode(self.offset, &mut self.current) {
+            Some(offset) => {
+                self.offset = offset;
+                self.remaining -= 1;
+                Some(CowBytes::from(&self.current[..]))
+            }
+            None => {
+                self.remaining = 0;
+                None
+            }
+        }
+    }
+}
+
+/// A leaf whose keys are front-coded. The key at position `i` belongs to
+/// `infos[i]` and `values[i]`.
+#[derive(
+    Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize,
+)]
+#[archive(check_bytes)]
+pub struct FrontCodedLeaf {
+    pub keys: FrontCodedKeys,
+    pub infos: Vec<KeyInfo>,
+    pub values: Vec<SlicedCowBytes>,
+}
+
+impl FrontCodedLeaf {
+    /// Builds the leaf from the entries of a `Type_A`/`Type_B` leaf.
+    pub fn from_entries(entries: &BTreeMap<CowBytes, (KeyInfo, SlicedCowBytes)>, restart_interval: usize) -> Self {
+        FrontCodedLeaf {
+            keys: FrontCodedKeys::build(entries.keys().map(|key| &key[..]), restart_interval),
+            infos: entries.values().map(|(info, _)| info.clone()).collect(),
+            values: entries.values().map(|(_, value)| value.clone()).collect(),
+        }
+    }
+
+    /// Copies the entries back into the map of a `Type_A`/`Type_B` leaf, or
+    /// returns `None` if the key section is malformed.
+    pub fn to_entries(&self) -> Option<BTreeMap<CowBytes, (KeyInfo, SlicedCowBytes)>> {
+        let entries = self.infos.iter().cloned().zip(self.values.iter().cloned());
+        Some(self.keys.view()?.iter().zip(entries).collect())
+    }
+
+    /// Returns the key info and value of `key`. Expects a leaf built by
+    /// [FrontCodedLeaf::from_entries] or decoded by [bincode_deserialize].
+    pub fn get(&self, key: &[u8]) -> Option<(&KeyInfo, &SlicedCowBytes)> {
+        let position = self.keys.view()?.find(key)?;
+        Some((self.infos.get(position)?, self.values.get(position)?))
+    }
+}
+
+impl ArchivedFrontCodedLeaf {
+    /// Returns the key info and value of `key`. Expects a leaf validated by
+    /// [rkyv_check].
+    pub fn get(&self, key: &[u8]) -> Option<(&ArchivedKeyInfo, &ArchivedSlicedCowBytes)> {
+        let position = self.keys.view()?.find(key)?;
+        Some((self.infos.get(position)?, self.values.get(position)?))
+    }
+}
+
+fn invalid(context: &str) -> Error {
+    Error::Validate { context: context.to_string() }
+}
+
+// Checks the key section of a leaf with `infos` key infos and `values`
+// values, shared by both codecs, which report the reason in their own phase.
+fn check_keys(view: Option<FrontCodedView>, infos: usize, values: usize) -> std::result::Result<(), &'static str> {
+    let view = view.ok_or("malformed key section trailer")?;
+    if view.len() != infos || view.len() != values {
+        return Err("key count does not match the number of values");
+    }
+    if !view.is_valid() {
+        return Err("malformed key section entry");
+    }
+    Ok(())
+}
+
+/// Validates `bytes` as an archived `FrontCodedLeaf`, including the key
+/// section and the bounds of all value slices.
+pub fn rkyv_check(bytes: &[u8]) -> Result<&ArchivedFrontCodedLeaf> {
+    let archived = codec::rkyv_check_value::<FrontCodedLeaf>(bytes)?;
+    check_keys(archived.keys.view(), archived.infos.len(), archived.values.len()).map_err(invalid)?;
+    if !archived.values.iter().all(|value| value.is_in_bounds()) {
+        return Err(invalid("value slice is out of bounds"));
+    }
+    Ok(archived)
+}
+
+/// Deserializes a bincode `FrontCodedLeaf` and validates it like
+/// [rkyv_check]. Values decoded by serde always cover their whole buffer.
+pub fn bincode_deserialize(bytes: &[u8]) -> Result<FrontCodedLeaf> {
+    let leaf: FrontCodedLeaf = codec::bincode_deserialize_value(bytes)?;
+    check_keys(leaf.keys.view(), leaf.infos.len(), leaf.values.len())
+        .map_err(|reason| Error::Deserialize { codec: Codec::Bincode, message: reason.to_string() })?;
+    Ok(leaf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::corruption;
+    use crate::test_support::sample_entries;
+
+    #[test]
+    fn varint_round_trip() {
+        for value in [0, 1, 127, 128, 300, u32::MAX as usize, usize::MAX] {
+            let mut out = CowBytes::new();
+            write_varint(&mut out, value);
+            let mut offset = 0;
+            assert_eq!(read_varint(&out, &mut offset), Some(value));
+            assert_eq!(offset, out.len());
+        }
+        assert_eq!(read_varint(&[0x80; 11], &mut 0), None);
+    }
+
+    #[test]
+    fn lookup_and_iteration() {
+        let entries = sample_entries(1000, 0);
+        for restart_interval in [1, 3, DEFAULT_RESTART_INTERVAL, 2000] {
+            let keys = FrontCodedKeys::build(entries.keys().map(|key| &key[..]), restart_interval);
+            let view = keys.view().unwrap();
+            assert_eq!(view.len(), entries.len());
+            assert!(view.iter().eq(entries.keys().cloned()));
+            for (position, key) in entries.keys().enumerate() {
+                assert_eq!(view.find(key), Some(position));
+                assert_eq!(view.get(position).as_ref(), Some(key));
+            }
+            for missing in [&b""[..], b"0", b"10000", b"5a", b"\xff"] {
+                assert_eq!(view.find(missing), None);
+            }
+            assert_eq!(view.get(entries.len()), None);
+        }
+    }
+
+    #[test]
+    fn empty_section() {
+        let keys = FrontCodedKeys::build(std::iter::empty(), DEFAULT_RESTART_INTERVAL);
+        let view = keys.view().unwrap();
+        assert!(view.is_empty() && view.is_valid());
+        assert_eq!(view.find(b"1"), None);
+        assert_eq!(view.iter().count(), 0);
+    }
+
+    #[test]
+    fn sequential_keys_compress() {
+        let entries = sample_entries(10000, 0);
+        let keys = FrontCodedKeys::build(entries.keys().map(|key| &key[..]), DEFAULT_RESTART_INTERVAL);
+        let plain: usize = entries.keys().map(|key| key.len()).sum();
+        assert!(keys.encoded_len() < plain, "{} >= {}", keys.encoded_len(), plain);
+    }
+
+    #[test]
+    fn leaf_round_trip() {
+        let entries = sample_entries(100, 16);
+        let leaf = FrontCodedLeaf::from_entries(&entries, 4);
+        assert_eq!(leaf.to_entries(), Some(entries.clone()));
+
+        let bytes = codec::rkyv_serialize_value(&leaf).unwrap();
+        let archived = rkyv_check(&bytes).unwrap();
+        assert_eq!(codec::rkyv_deserialize_value::<FrontCodedLeaf>(archived).unwrap(), leaf);
+        for (key, (info, value)) in entries.iter() {
+            let (archived_info, archived_value) = archived.get(key).unwrap();
+            assert_eq!(&KeyInfo::from(archived_info), info);
+            assert_eq!(archived_value.data.inner.as_slice(), &value[..]);
+        }
+
+        let bytes = codec::bincode_serialize_value(&leaf).unwrap();
+        let deserialized = bincode_deserialize(&bytes).unwrap();
+        for (key, (info, value)) in entries.iter() {
+            assert_eq!(deserialized.get(key), Some((info, value)));
+        }
+    }
+
+    #[test]
+    fn malformed_sections_do_not_panic() {
+        let entries = sample_entries(100, 0);
+        let keys = FrontCodedKeys::build(entries.keys().map(|key| &key[..]), 4);
+        for len in 0..keys.data.len() {
+            for flip in [0u8, 0x01, 0x80] {
+                let mut bytes = keys.data[..].to_vec();
+                bytes[len] ^= flip;
+                for bytes in [&bytes[..], &bytes[..len]] {
+                    if let Some(view) = FrontCodedView::new(bytes) {
+                        view.iter().count();
+                        view.find(b"50");
+                        view.get(50);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn corrupted_bincode_is_rejected() {
+        let leaf = FrontCodedLeaf::from_entries(&sample_entries(32, 4), 4);
+        let bytes = codec::bincode_serialize_value(&leaf).unwrap();
+        let mut rejected = 0;
+        for mutation in corruption::exhaustive_mutations(bytes.len()) {
+            match bincode_deserialize(&mutation.apply(&bytes)) {
+                Ok(deserialized) => {
+                    assert!(deserialized.keys.view().unwrap().is_valid(), "{:?}", mutation);
+                    for key in sample_entries(32, 0).keys() {
+                        deserialized.get(key);
+                    }
+                    deserialized.to_entries().unwrap();
+                }
+                Err(_) => rejected += 1,
+            }
+        }
+        assert!(rejected > 0);
+
+        // Structurally valid bincode with fewer values than keys.
+        let mut short = leaf.clone();
+        short.values.pop();
+        let err = bincode_deserialize(&codec::bincode_serialize_value(&short).unwrap()).unwrap_err();
+        assert_eq!(err.phase(), "deserialize");
+        assert!(err.to_string().starts_with("bincode"), "{}", err);
+
+        // A restart point which shares a prefix with its predecessor.
+        let mut keys = leaf.keys.data[..].to_vec();
+        keys[0] = 1;
+        let shared = FrontCodedLeaf { keys: FrontCodedKeys { data: CowBytes::from(keys) }, ..leaf };
+        assert!(bincode_deserialize(&codec::bincode_serialize_value(&shared).unwrap()).is_err());
+    }
+}
@@ -0,0 +1,239 @@
+//! This module provides golden test vectors: a small canonical input per
+//! scenario, together with its expected encoding per codec.
+//!
+//! The expected encodings are committed to `fixtures/vectors` as
+//! `<scenario>.<codec>`. The tests fail as soon as the bytes written for any
+//! input change, e.g. after changing the layout of `CowBytes` or bumping
+//! rkyv or bincode, and check that the committed bytes still decode to the
+//! input. The rkyv vectors are little endian with 32-bit offsets and only
+//! checked by builds writing this [ArchiveFormat].
+//!
+//! After a deliberate change of an encoding, write the vectors anew with
+//!
+//! ```text
+//! cargo test regenerate_vectors -- --ignored
+//! ```
+//!
+//! and commit them together with the change. This also writes the vectors
+//! of the [BINARY_SCENARIOS], whose node types are private to their binaries
+//! and checked there. Archives of older leaf layouts
+//! which have to stay readable are covered by [crate::versioned] instead.
+
+use crate::archive_format::{self, ArchiveFormat};
+use crate::codec::{self, Codec};
+use crate::cow_bytes::{CowBytes, SlicedCowBytes};
+use crate::error::{Error, Result};
+use crate::front_coding::FrontCodedLeaf;
+use crate::indexed_leaf::IndexedLeaf;
+use crate::internal::{sample_internal_node, InternalNode};
+use crate::key_info::KeyInfo;
+use crate::message::{sample_message, Message};
+use crate::storage_preference::{AtomicStoragePreference, AtomicSystemStoragePreference, StoragePreference};
+use crate::test_support::sample_entries;
+use crate::{Type_A, Type_B};
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use rkyv::de::deserializers::SharedDeserializeMap;
+use rkyv::ser::serializers::AllocSerializer;
+use rkyv::validation::validators::DefaultValidator;
+use rkyv::{AlignedVec, CheckBytes};
+use serde::de::DeserializeOwned;
+
+type Decodes = Box<dyn Fn(&[u8]) -> Result<bool>>;
+
+/// The expected encoding of the input of one scenario.
+pub struct Vector {
+    pub scenario: &'static str,
+    pub codec: Codec,
+    pub bytes: Vec<u8>,
+    // Decodes an encoding and compares it with the input.
+    decodes: Decodes,
+}
+
+impl Vector {
+    /// Name of the file holding the expected encoding.
+    pub fn file_name(&self) -> String {
+        format!("{}.{}", self.scenario, self.codec)
+    }
+
+    /// Returns whether `bytes` decode to the input of the scenario.
+    pub fn decodes(&self, bytes: &[u8]) -> Result<bool> {
+        (self.decodes)(bytes)
+    }
+}
+
+fn rkyv_vector<T>(scenario: &'static str, input: T) -> Result<Vector>
+where
+    T: rkyv::Archive + rkyv::Serialize<AllocSerializer<0>> + PartialEq + 'static,
+    T::Archived: for<'a> CheckBytes<DefaultValidator<'a>> + rkyv::Deserialize<T, SharedDeserializeMap>,
+{
+    let bytes = codec::rkyv_serialize_value(&input)?.to_vec();
+    let decodes = move |bytes: &[u8]| {
+        // Files are read into unaligned buffers.
+        let mut aligned = AlignedVec::with_capacity(bytes.len());
+        aligned.extend_from_slice(bytes);
+        let archived = codec::rkyv_check_value::<T>(&aligned)?;
+        Ok(codec::rkyv_deserialize_value::<T>(archived)? == input)
+    };
+    Ok(Vector { scenario, codec: Codec::Rkyv, bytes, decodes: Box::new(decodes) })
+}
+
+fn bincode_vector<T>(scenario: &'static str, input: T) -> Result<Vector>
+where
+    T: serde::Serialize + DeserializeOwned + PartialEq + 'static,
+{
+    let bytes = codec::bincode_serialize_value(&input)?;
+    let decodes = move |bytes: &[u8]| Ok(codec::bincode_deserialize_value::<T>(bytes)? == input);
+    Ok(Vector { scenario, codec: Codec::Bincode, bytes, decodes: Box::new(decodes) })
+}
+
+fn sliced_entries() -> BTreeMap<CowBytes, (KeyInfo, SlicedCowBytes)> {
+    let mut entries = sample_entries(2, 4);
+    let info = KeyInfo::new(StoragePreference::FAST).with_sequence(1).with_ttl(Duration::from_secs(30)).with_flags(1);
+    entries.insert(CowBytes::from(&b"sliced"[..]), (info, CowBytes::from(&b"0123456789"[..]).slice(2, 4)));
+    entries
+}
+
+fn internal_node() -> InternalNode {
+    sample_internal_node(2, 2, |number| sample_message(number, 4))
+}
+
+fn messages() -> Vec<Message> {
+    (0..3).map(|number| sample_message(number, 4)).collect()
+}
+
+fn key_infos() -> Vec<KeyInfo> {
+    vec![
+        KeyInfo::default(),
+        KeyInfo::new(StoragePreference::SLOW).with_sequence(7).with_ttl(Duration::from_secs(3600)).with_flags(0x81),
+    ]
+}
+
+type Preferences = (StoragePreference, AtomicStoragePreference, AtomicStoragePreference, AtomicSystemStoragePreference);
+
+fn storage_preferences() -> Preferences {
+    (
+        StoragePreference::FAST,
+        AtomicStoragePreference::known(StoragePreference::SLOW),
+        AtomicStoragePreference::unknown(),
+        AtomicSystemStoragePreference::from(StoragePreference::FASTEST),
+    )
+}
+
+/// Scenarios of the standalone binaries, whose vectors are checked against
+/// their real node types by the tests of each `main_<scenario>.rs`.
+pub const BINARY_SCENARIOS: [&str; 6] =
+    ["mp_of_ints", "mp_of_strs", "vt_of_cowbytes", "vt_of_ints", "vt_of_strs", "example_from_rkyvsite"];
+
+/// All vectors, by scenario and codec.
+pub fn vectors() -> Result<Vec<Vector>> {
+    let entries = || sample_entries(4, 8);
+    Ok(vec![
+        rkyv_vector("leaf", Type_A { entries: entries() })?,
+        bincode_vector("leaf", Type_B { entries: entries() })?,
+        rkyv_vector("leaf_sliced", Type_A { entries: sliced_entries() })?,
+        bincode_vector("leaf_sliced", Type_B { entries: sliced_entries() })?,
+        rkyv_vector("internal", internal_node())?,
+        bincode_vector("internal", internal_node())?,
+        rkyv_vector("messages", messages())?,
+        bincode_vector("messages", messages())?,
+        rkyv_vector("indexed_leaf", IndexedLeaf::from_entries(&entries())?)?,
+        bincode_vector("indexed_leaf", IndexedLeaf::from_entries(&entries())?)?,
+        rkyv_vector("front_coded_leaf", FrontCodedLeaf::from_entries(&entries(), 2))?,
+        bincode_vector("front_coded_leaf", FrontCodedLeaf::from_entries(&entries(), 2))?,
+        rkyv_vector("key_info", key_infos())?,
+        bincode_vector("key_info", key_infos())?,
+        rkyv_vector("storage_preference", storage_preferences())?,
+        bincode_vector("storage_preference", storage_preferences())?,
+    ])
+}
+
+/// Directory holding the committed vectors.
+pub fn vector_dir() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("fixtures/vectors")
+}
+
+/// Writes all vectors into `dir`, replacing the existing ones.
+pub fn regenerate(dir: &Path) -> Result<()> {
+    if !archive_format::reads_fixtures() {
+        let message = format!("vectors are written in {}, not {}", ArchiveFormat::FIXTURES, ArchiveFormat::CURRENT);
+        return Err(Error::Config { message });
+    }
+    fs::create_dir_all(dir)?;
+    for vector in vectors()? {
+        fs::write(dir.join(vector.file_name()), &vector.bytes)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeSet;
+
+    /// The vectors of `codec`.
+    fn codec_vectors(codec: Codec) -> Vec<Vector> {
+        let mut vectors = vectors().unwrap();
+        vectors.retain(|vector| vector.codec == codec);
+        vectors
+    }
+
+    fn check_committed(codec: Codec) {
+        let mut changed = Vec::new();
+        for vector in codec_vectors(codec) {
+            match fs::read(vector_dir().join(vector.file_name())) {
+                Ok(bytes) if bytes == vector.bytes => {
+                    assert!(vector.decodes(&bytes).unwrap(), "{}", vector.file_name());
+                }
+                _ => changed.push(vector.file_name()),
+            }
+        }
+        assert!(changed.is_empty(), "encodings changed: {:?}, see the docs of golden.rs", changed);
+    }
+
+    #[test]
+    fn bincode_encodings_match_vectors() {
+        check_committed(Codec::Bincode);
+    }
+
+    #[test]
+    #[cfg_attr(
+        any(feature = "archive_be", feature = "size_64", all(target_endian = "big", not(feature = "archive_le"))),
+        ignore = "the rkyv vectors are le/32-bit archives"
+    )]
+    fn rkyv_encodings_match_vectors() {
+        assert!(archive_format::reads_fixtures());
+        check_committed(Codec::Rkyv);
+    }
+
+    #[test]
+    fn vectors_decode_to_input() {
+        for vector in vectors().unwrap() {
+            assert!(vector.decodes(&vector.bytes).unwrap(), "{}", vector.file_name());
+        }
+    }
+
+    #[test]
+    fn no_stale_vectors() {
+        let expected: BTreeSet<_> = vectors().unwrap().iter().map(Vector::file_name).collect();
+        let committed: BTreeSet<_> = fs::read_dir(vector_dir())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .filter(|name| !BINARY_SCENARIOS.iter().any(|scenario| name.split('.').next() == Some(*scenario)))
+            .collect();
+        assert_eq!(committed, expected);
+    }
+
+    /// Writes the vectors, see the docs of this module.
+    #[test]
+    #[ignore]
+    fn regenerate_vectors() {
+        regenerate(&vector_dir()).unwrap();
+    }
+}
@@ -0,0 +1,210 @@
+//! This module provides in-place updates of an archived `Type_A`.
+//!
+//! Fields of fixed size, like the storage preference of an entry, and the
+//! bytes of a value can be overwritten directly in the buffer, e.g. in a
+//! writable mapping of the node. Anything which changes the length of a
+//! value or the set of keys still requires serializing the node anew.
+//!
+//! The archived node is only accessed through `Pin<&mut _>`, as its relative
+//! pointers must not move. The projections below only overwrite bytes and
+//! never move or replace the archived vectors.
+
+use crate::codec;
+use crate::error::{Error, Result};
+use crate::leaf::ArchivedEntry;
+use crate::storage_preference::StoragePreference;
+use crate::{ArchivedType_A, Type_A};
+
+use std::pin::Pin;
+
+use rkyv::validation::validators::DefaultValidator;
+use rkyv::{Archive, CheckBytes};
+
+/// Validates `bytes` like [codec::rkyv_check] and returns the archived node
+/// for updating it in place.
+pub fn rkyv_check_mut(bytes: Pin<&mut [u8]>) -> Result<Pin<&mut ArchivedType_A>> {
+    codec::rkyv_check(&bytes)?;
+    // The buffer was validated above, including the bounds of all slices.
+    Ok(unsafe { rkyv::archived_root_mut::<Type_A>(bytes) })
+}
+
+/// Validates `bytes` like [codec::rkyv_check_value] and returns the archived
+/// root of type `T` for updating it in place, e.g. its archived storage
+/// preferences.
+pub fn rkyv_check_value_mut<'a, T: Archive>(bytes: Pin<&'a mut [u8]>) -> Result<Pin<&'a mut T::Archived>>
+where
+    T::Archived: for<'b> CheckBytes<DefaultValidator<'b>>,
+{
+    codec::rkyv_check_value::<T>(&bytes)?;
+    // Validated above.
+    Ok(unsafe { rkyv::archived_root_mut::<T>(bytes) })
+}
+
+fn missing(key: &[u8]) -> Error {
+    Error::InPlace { reason: format!("key {:?} is not present", String::from_utf8_lossy(key)) }
+}
+
+impl ArchivedType_A {
+    /// Returns the entry of `key`.
+    pub fn entry_mut(self: Pin<&mut Self>, key: &[u8]) -> Option<Pin<&mut ArchivedEntry>> {
+        let idx = self.entries.binary_search_by(|entry| entry.key.as_ref().cmp(key)).ok()?;
+        // Projecting to a field does not move the node.
+        let entries = unsafe { self.map_unchecked_mut(|node| &mut node.entries) };
+        Some(entries.index_pin(idx))
+    }
+
+    /// Sets the storage preference of `key`.
+    pub fn set_storage_preference(self: Pin<&mut Self>, key: &[u8], pref: StoragePreference) -> Result<()> {
+        let entry = self.entry_mut(key).ok_or_else(|| missing(key))?;
+        // Projecting to a field does not move the entry.
+        unsafe { entry.map_unchecked_mut(|entry| &mut entry.value.0) }.set_storage_preference(pref);
+        Ok(())
+    }
+
+    /// Overwrites the value of `key` with `value`, which must be as long as
+    /// the current value.
+    pub fn overwrite_value(self: Pin<&mut Self>, key: &[u8], value: &[u8]) -> Result<()> {
+        let entry = self.entry_mut(key).ok_or_else(|| missing(key))?;
+        let sliced = unsafe { entry.map_unchecked_mut(|entry| &mut entry.value.1) };
+        let target = sliced
+            .bytes_mut()
+            .ok_or_else(|| Error::InPlace { reason: "archived slice is out of bounds".to_string() })?;
+        if target.len() != value.len() {
+            return Err(Error::InPlace {
+                reason: format!("new value has {} bytes, the archived one {}", value.len(), target.len()),
+            });
+        }
+        target.copy_from_slice(value);
+        Ok(())
+    }
+}
+
+/// Sets the storage preference of `key` by deserializing the node, updating
+/// it and serializing it again; the baseline for the in-place update.
+pub fn reserialize_storage_preference(
+    bytes: &[u8],
+    key: &[u8],
+    pref: StoragePreference,
+) -> Result<rkyv::AlignedVec> {
+    let mut node = codec::rkyv_deserialize(codec::rkyv_check(bytes)?)?;
+    let (info, _) = node.entries.get_mut(key).ok_or_else(|| missing(key))?;
+    info.set_storage_preference(pref);
+    codec::rkyv_serialize(&node)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::sample_entries;
+    use crate::cow_bytes::{CowBytes, SlicedCowBytes};
+    use crate::stream;
+    use crate::key_info::KeyInfo;
+    use crate::storage_preference::{AtomicStoragePreference, AtomicSystemStoragePreference};
+
+    #[test]
+    fn update_storage_preference() {
+        let mut node = Type_A { entries: sample_entries(16, 32) };
+        let mut bytes = codec::rkyv_serialize(&node).unwrap();
+        let reserialized = reserialize_storage_preference(&bytes, b"7", StoragePreference::FASTEST).unwrap();
+
+        let mut archived = rkyv_check_mut(Pin::new(&mut bytes[..])).unwrap();
+        archived.as_mut().set_storage_preference(b"7", StoragePreference::FASTEST).unwrap();
+        let err = archived.set_storage_preference(b"missing", StoragePreference::FASTEST).unwrap_err();
+        assert_eq!(err.phase(), "in-place update");
+
+        node.entries.get_mut(&b"7"[..]).unwrap().0 = KeyInfo::new(StoragePreference::FASTEST);
+        assert_eq!(codec::rkyv_deserialize(codec::rkyv_check(&bytes).unwrap()).unwrap(), node);
+        assert_eq!(&bytes[..], &reserialized[..]);
+    }
+
+    #[test]
+    fn overwrite_sliced_value() {
+        let mut entries = sample_entries(16, 32);
+        let sliced = CowBytes::from(vec![0u8; 32]).slice(8, 4);
+        let info = KeyInfo::new(StoragePreference::NONE);
+        entries.insert(CowBytes::from(&b"sliced"[..]), (info, sliced));
+        let mut node = Type_A { entries };
+        let mut bytes = codec::rkyv_serialize(&node).unwrap();
+
+        let mut archived = rkyv_check_mut(Pin::new(&mut bytes[..])).unwrap();
+        archived.as_mut().overwrite_value(b"sliced", b"abcd").unwrap();
+        archived.as_mut().overwrite_value(b"3", &[7u8; 32]).unwrap();
+        assert_eq!(archived.overwrite_value(b"3", &[7u8; 31]).unwrap_err().phase(), "in-place update");
+
+        let deserialized = codec::rkyv_deserialize(codec::rkyv_check(&bytes).unwrap()).unwrap();
+        assert_eq!(&deserialized.entries[&b"sliced"[..]].1[..], b"abcd");
+        node.entries.get_mut(&b"sliced"[..]).unwrap().1 = SlicedCowBytes::from(CowBytes::from(&b"abcd"[..]));
+        node.entries.get_mut(&b"3"[..]).unwrap().1 = SlicedCowBytes::from(CowBytes::from(vec![7u8; 32]));
+        assert_eq!(deserialized, node);
+    }
+
+    #[test]
+    fn update_mapped_file() {
+        let node = Type_A { entries: sample_entries(16, 32) };
+        let path = stream::scratch_path("update_mapped_file");
+        stream::rkyv_to_file(&codec::rkyv_serialize(&node).unwrap(), &path).unwrap();
+
+        let mut map = stream::rkyv_map_file_mut(&path).unwrap();
+        let archived = rkyv_check_mut(Pin::new(&mut map[..])).unwrap();
+        archived.set_storage_preference(b"1", StoragePreference::SLOW).unwrap();
+        map.flush().unwrap();
+        drop(map);
+
+        let map = stream::rkyv_map_file(&path).unwrap();
+        let archived = codec::rkyv_check(&map).unwrap();
+        let idx = archived.entries.binary_search_by(|entry| entry.key.as_ref().cmp(b"1")).unwrap();
+        let entry = &archived.entries[idx];
+        assert_eq!(entry.value.0.storage_preference(), StoragePreference::SLOW);
+        drop(map);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn atomic_archive_round_trip() {
+        for pref in [AtomicStoragePreference::unknown(), AtomicStoragePreference::known(StoragePreference::SLOW)] {
+            let bytes = codec::rkyv_serialize_value(&pref).unwrap();
+            let archived = codec::rkyv_check_value::<AtomicStoragePreference>(&bytes).unwrap();
+            assert_eq!(archived.as_option(), pref.as_option());
+            assert_eq!(AtomicStoragePreference::from(archived), pref);
+            assert_eq!(codec::rkyv_deserialize_value::<AtomicStoragePreference>(archived).unwrap(), pref);
+        }
+
+        let pref = AtomicSystemStoragePreference::from(StoragePreference::FAST);
+        let bytes = codec::rkyv_serialize_value(&pref).unwrap();
+        let archived = codec::rkyv_check_value::<AtomicSystemStoragePreference>(&bytes).unwrap();
+        assert_eq!(StoragePreference::from(archived), StoragePreference::FAST);
+        assert_eq!(AtomicSystemStoragePreference::from(archived), pref);
+        assert_eq!(codec::rkyv_deserialize_value::<AtomicSystemStoragePreference>(archived).unwrap(), pref);
+    }
+
+    #[test]
+    fn atomic_archive_update_in_place() {
+        let pref = AtomicStoragePreference::known(StoragePreference::SLOWEST);
+        let mut bytes = codec::rkyv_serialize_value(&pref).unwrap();
+        let mut archived = rkyv_check_value_mut::<AtomicStoragePreference>(Pin::new(&mut bytes[..])).unwrap();
+        archived.as_mut().upgrade(StoragePreference::FAST);
+        assert_eq!(archived.as_option(), Some(StoragePreference::FAST));
+        archived.as_mut().upgrade(StoragePreference::SLOW);
+        assert_eq!(archived.as_option(), Some(StoragePreference::FAST));
+        let archived = codec::rkyv_check_value::<AtomicStoragePreference>(&bytes).unwrap();
+        assert_eq!(AtomicStoragePreference::from(archived).as_option(), Some(StoragePreference::FAST));
+
+        let mut archived = rkyv_check_value_mut::<AtomicStoragePreference>(Pin::new(&mut bytes[..])).unwrap();
+        archived.as_mut().invalidate();
+        archived.as_mut().upgrade(StoragePreference::FASTEST);
+        assert_eq!(archived.unwrap_or_none(), StoragePreference::NONE);
+        archived.as_mut().set(StoragePreference::SLOW);
+        assert_eq!(archived.as_option(), Some(StoragePreference::SLOW));
+
+        let mut bytes = codec::rkyv_serialize_value(&AtomicSystemStoragePreference::none()).unwrap();
+        let archived =
+            rkyv_check_value_mut::<AtomicSystemStoragePreference>(Pin::new(&mut bytes[..])).unwrap();
+        archived.set(StoragePreference::SLOW);
+        let archived = codec::rkyv_check_value::<AtomicSystemStoragePreference>(&bytes).unwrap();
+        assert_eq!(StoragePreference::from(archived), StoragePreference::SLOW);
+
+        // Invalid archives are rejected before anything is written.
+        let err = rkyv_check_value_mut::<AtomicSystemStoragePreference>(Pin::new(&mut bytes[..0]));
+        assert!(err.is_err());
+    }
+}
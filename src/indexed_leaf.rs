@@ -0,0 +1,284 @@
+//! This module provides `IndexedLeaf`, a leaf layout which stores a compact
+//! key index in front of a heap holding all values.
+//!
+//! In `Type_A` the archived key bytes are interleaved with the value bytes, so
+//! a key scan touches memory all over the node. Here the index holds the keys,
+//! their `KeyInfo` and the location of their value in the heap, so a scan only
+//! reads the index.
+//!
+//! For bincode, [IndexedLeaf::bincode_write] and [BincodeIndexedLeaf] encode
+//! and read the same bytes as `bincode::serialize` would, but the reader
+//! parses only the index and borrows values from the input.
+
+use crate::codec::{self, Codec};
+use crate::cow_bytes::{CowBytes, SlicedCowBytes};
+use crate::error::{Error, Result};
+use crate::key_info::KeyInfo;
+
+use std::{collections::BTreeMap, convert::TryInto, ops::Range};
+
+/// Location of one value within the heap of an [IndexedLeaf].
+#[derive(
+    Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize,
+)]
+#[archive(check_bytes)]
+pub struct IndexEntry {
+    pub key: CowBytes,
+    pub info: KeyInfo,
+    pub offset: u32,
+    pub len: u32,
+}
+
+/// A leaf with a key index, sorted by key, followed by a value heap.
+#[derive(
+    Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize,
+)]
+#[archive(check_bytes)]
+pub struct IndexedLeaf {
+    pub index: Vec<IndexEntry>,
+    pub values: CowBytes,
+}
+
+fn value_range(offset: u32, len: u32) -> Range<usize> {
+    offset as usize..offset as usize + len as usize
+}
+
+// Returns [Error::SizeLimit] unless the value locations of a heap of
+// `heap_len` bytes fit into the `u32` fields of an [IndexEntry].
+fn check_heap_len(heap_len: usize) -> Result<()> {
+    match u32::try_from(heap_len) {
+        Ok(_) => Ok(()),
+        Err(_) => Err(Error::SizeLimit { codec: Codec::Rkyv, size: Some(heap_len), limit: u32::MAX as usize }),
+    }
+}
+
+impl IndexedLeaf {
+    /// Builds the layout from the entries of a `Type_A`/`Type_B` leaf. Fails
+    /// if the values take up more than 4 GiB, which the offsets of the index
+    /// cannot address.
+    pub fn from_entries(entries: &BTreeMap<CowBytes, (KeyInfo, SlicedCowBytes)>) -> Result<Self> {
+        let heap_len = entries.values().map(|(_, value)| value.len()).sum();
+        check_heap_len(heap_len)?;
+        let mut values = CowBytes::with_capacity(heap_len);
+        let index = entries
+            .iter()
+            .map(|(key, (info, value))| {
+                // Both are at most `heap_len`, which fits.
+                let offset = values.len() as u32;
+                values.push_slice(value);
+                IndexEntry { key: key.clone(), info: info.clone(), offset, len: value.len() as u32 }
+            })
+            .collect();
+        Ok(IndexedLeaf { index, values })
+    }
+
+    /// Copies the entries back into the map of a `Type_A`/`Type_B` leaf.
+    pub fn to_entries(&self) -> BTreeMap<CowBytes, (KeyInfo, SlicedCowBytes)> {
+        self.index
+            .iter()
+            .map(|entry| {
+                let value = CowBytes::from(&self.values[value_range(entry.offset, entry.len)]);
+                (entry.key.clone(), (entry.info.clone(), SlicedCowBytes::from(value)))
+            })
+            .collect()
+    }
+
+    /// Appends the bincode encoding of the leaf to `out`, without going
+    /// through serde.
+    pub fn bincode_write(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&(self.index.len() as u64).to_le_bytes());
+        for entry in self.index.iter() {
+            out.extend_from_slice(&(entry.key.len() as u64).to_le_bytes());
+            out.extend_from_slice(&entry.key);
+            out.extend_from_slice(&entry.info.bincode_encode());
+            out.extend_from_slice(&entry.offset.to_le_bytes());
+            out.extend_from_slice(&entry.len.to_le_bytes());
+        }
+        out.extend_from_slice(&(self.values.len() as u64).to_le_bytes());
+        out.extend_from_slice(&self.values);
+    }
+}
+
+impl ArchivedIndexedLeaf {
+    /// Returns the index entry of `key`.
+    pub fn entry(&self, key: &[u8]) -> Option<&ArchivedIndexEntry> {
+        let idx = self.index.binary_search_by(|entry| entry.key.as_ref().cmp(key)).ok()?;
+        Some(&self.index[idx])
+    }
+
+    /// Returns the value belonging to `entry`, or `None` if its location is
+    /// not within the heap.
+    pub fn value(&self, entry: &ArchivedIndexEntry) -> Option<&[u8]> {
+        self.values.as_ref().get(value_range(rkyv::from_archived!(entry.offset), rkyv::from_archived!(entry.len)))
+    }
+
+    /// Returns the keys in order, without touching the heap.
+    pub fn keys(&self) -> impl Iterator<Item = &[u8]> {
+        self.index.iter().map(|entry| entry.key.as_ref())
+    }
+}
+
+/// Validates `bytes` as an archived `IndexedLeaf`, including the locations
+/// of all values.
+pub fn rkyv_check(bytes: &[u8]) -> Result<&ArchivedIndexedLeaf> {
+    let archived = codec::rkyv_check_value::<IndexedLeaf>(bytes)?;
+    if archived.index.iter().any(|entry| archived.value(entry).is_none()) {
+        return Err(Error::Validate { context: "value location is out of bounds".to_string() });
+    }
+    Ok(archived)
+}
+
+/// The index of a bincode encoded `IndexedLeaf`, borrowing keys and values
+/// from the encoded bytes.
+#[derive(Debug)]
+pub struct BincodeIndexedLeaf<'a> {
+    index: Vec<(&'a [u8], KeyInfo, Range<usize>)>,
+    values: &'a [u8],
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.bytes.len() < len {
+            return Err(Error::Deserialize {
+                codec: Codec::Bincode,
+                message: format!("expected {} more bytes, found {}", len, self.bytes.len()),
+            });
+        }
+        let (head, tail) = self.bytes.split_at(len);
+        self.bytes = tail;
+        Ok(head)
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn len(&mut self) -> Result<usize> {
+        let len = u64::from_le_bytes(self.take(8)?.try_into().unwrap());
+        // Every length prefixes at least as many bytes, which bounds it by
+        // the input and keeps the preallocation below in check.
+        match usize::try_from(len) {
+            Ok(len) if len <= self.bytes.len() => Ok(len),
+            _ => Err(Error::Deserialize {
+                codec: Codec::Bincode,
+                message: format!("length {} exceeds the remaining {} bytes", len, self.bytes.len()),
+            }),
+        }
+    }
+
+    fn bytes(&mut self) -> Result<&'a [u8]> {
+        let len = self.len()?;
+        self.take(len)
+    }
+}
+
+impl<'a> BincodeIndexedLeaf<'a> {
+    /// Parses the index of the encoded leaf in `bytes`. Values are only
+    /// located, not read.
+    pub fn parse(bytes: &'a [u8]) -> Result<Self> {
+        let mut reader = Reader { bytes };
+        let count = reader.len()?;
+        let mut index = Vec::with_capacity(count);
+        for _ in 0..count {
+            let key = reader.bytes()?;
+            let info = KeyInfo::bincode_decode(reader.take(KeyInfo::BINCODE_SIZE)?)?;
+            let (offset, len) = (reader.u32()?, reader.u32()?);
+            index.push((key, info, value_range(offset, len)));
+        }
+        let values = reader.bytes()?;
+        if index.iter().any(|(_, _, range)| range.end > values.len()) {
+            return Err(Error::Deserialize {
+                codec: Codec::Bincode,
+                message: "value location is out of bounds".to_string(),
+            });
+        }
+        Ok(BincodeIndexedLeaf { index, values })
+    }
+
+    /// Returns the key info and value of `key`.
+    pub fn get(&self, key: &[u8]) -> Option<(&KeyInfo, &'a [u8])> {
+        let idx = self.index.binary_search_by(|(k, _, _)| (*k).cmp(key)).ok()?;
+        let (_, info, range) = &self.index[idx];
+        Some((info, &self.values[range.clone()]))
+    }
+
+    /// Returns the keys in order.
+    pub fn keys(&self) -> impl Iterator<Item = &'a [u8]> + '_ {
+        self.index.iter().map(|(key, _, _)| *key)
+    }
+
+    /// Number of entries.
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    /// Returns whether the leaf has no entries.
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::sample_entries;
+
+    #[test]
+    fn entries_round_trip() {
+        let entries = sample_entries(100, 100);
+        let leaf = IndexedLeaf::from_entries(&entries).unwrap();
+        assert_eq!(leaf.to_entries(), entries);
+
+        let bytes = codec::rkyv_serialize_value(&leaf).unwrap();
+        let archived = rkyv_check(&bytes).unwrap();
+        assert_eq!(codec::rkyv_deserialize_value::<IndexedLeaf>(archived).unwrap(), leaf);
+        assert!(archived.keys().eq(entries.keys().map(|key| &key[..])));
+        for (key, (_, value)) in entries.iter() {
+            assert_eq!(archived.value(archived.entry(key).unwrap()), Some(&value[..]));
+        }
+        assert!(archived.entry(b"missing").is_none());
+    }
+
+    #[test]
+    fn hand_rolled_bincode_matches_serde() {
+        let entries = sample_entries(100, 100);
+        let leaf = IndexedLeaf::from_entries(&entries).unwrap();
+        let mut bytes = Vec::new();
+        leaf.bincode_write(&mut bytes);
+        assert_eq!(bytes, codec::bincode_serialize_value(&leaf).unwrap());
+
+        let parsed = BincodeIndexedLeaf::parse(&bytes).unwrap();
+        assert_eq!(parsed.len(), entries.len());
+        assert!(parsed.keys().eq(entries.keys().map(|key| &key[..])));
+        for (key, (info, value)) in entries.iter() {
+            assert_eq!(parsed.get(key), Some((info, &value[..])));
+        }
+    }
+
+    #[test]
+    fn out_of_bounds_values_are_rejected() {
+        let mut leaf = IndexedLeaf::from_entries(&sample_entries(4, 16)).unwrap();
+        leaf.index[3].len += 1;
+
+        let bytes = codec::rkyv_serialize_value(&leaf).unwrap();
+        assert_eq!(rkyv_check(&bytes).err().unwrap().phase(), "validate");
+
+        let mut bytes = Vec::new();
+        leaf.bincode_write(&mut bytes);
+        assert_eq!(BincodeIndexedLeaf::parse(&bytes).unwrap_err().phase(), "deserialize");
+        for len in 0..bytes.len() {
+            assert_eq!(BincodeIndexedLeaf::parse(&bytes[..len]).unwrap_err().phase(), "deserialize");
+        }
+    }
+
+    #[test]
+    fn oversized_heaps_are_rejected() {
+        assert!(check_heap_len(u32::MAX as usize).is_ok());
+        let err = check_heap_len(u32::MAX as usize + 1).unwrap_err();
+        assert!(matches!(err, Error::SizeLimit { size: Some(size), .. } if size == u32::MAX as usize + 1));
+    }
+}
@@ -0,0 +1,385 @@
+//! This module provides `InternalNode`, the internal node of a B-epsilon
+//! tree.
+//!
+//! In contrast to the leaf (`Type_A`/`Type_B`), an internal node holds pivot
+//! keys and one `ChildBuffer` per child, which collects the messages that have
+//! not been flushed down to that child yet. Unlike the leaf, the same type
+//! derives both serde and rkyv.
+//!
+//! The storage preference of a node is computed bottom-up: each child buffer
+//! caches the fastest preference of its child and its buffered messages, and
+//! [InternalNode::storage_preference] combines the cached ones. Inserting a
+//! message can only make a child faster and upgrades the cache in place.
+//! Any other modification of a child invalidates its cache with
+//! [InternalNode::invalidate_child], to be recomputed on the next query.
+
+use crate::codec;
+use crate::cow_bytes::CowBytes;
+use crate::error::{Error, Result};
+use crate::key_info::KeyInfo;
+use crate::message::Message;
+use crate::storage_preference::{AtomicStoragePreference, StoragePreference};
+
+use std::{cmp::Ordering, collections::BTreeMap};
+
+/// Location of a child node on disk.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize, rkyv::Archive, rkyv::Serialize,
+    rkyv::Deserialize,
+)]
+#[archive(check_bytes)]
+pub struct ChildPointer {
+    pub offset: u64,
+    pub size: u32,
+}
+
+/// A child reference together with the messages buffered for it.
+#[derive(
+    Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize,
+)]
+#[archive(check_bytes)]
+pub struct ChildBuffer {
+    pub pointer: ChildPointer,
+    /// The fastest storage preference of the child and all buffered
+    /// messages, unknown if it needs to be recomputed.
+    pub storage_preference: AtomicStoragePreference,
+    #[with(rkyv::with::AsVec)]
+    pub messages: BTreeMap<CowBytes, (KeyInfo, Message)>,
+}
+
+/// An internal node with `pivots.len() + 1` children.
+///
+/// A key `k` belongs to the first child `i` with `k <= pivots[i]`, or to the
+/// last child if it is larger than all pivots.
+#[derive(
+    Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize,
+)]
+#[archive(check_bytes)]
+pub struct InternalNode {
+    pub level: u32,
+    pub pivots: Vec<CowBytes>,
+    pub children: Vec<ChildBuffer>,
+}
+
+fn child_index_by(pivots: usize, cmp: impl Fn(usize) -> Ordering) -> usize {
+    // Binary search for the first pivot which is not smaller than the key.
+    let (mut low, mut high) = (0, pivots);
+    while low < high {
+        let mid = low + (high - low) / 2;
+        match cmp(mid) {
+            Ordering::Less => low = mid + 1,
+            _ => high = mid,
+        }
+    }
+    low
+}
+
+impl InternalNode {
+    /// Returns the index of the child responsible for `key`.
+    pub fn child_index(&self, key: &[u8]) -> usize {
+        child_index_by(self.pivots.len(), |i| self.pivots[i][..].cmp(key))
+    }
+
+    /// Buffers a message for the child responsible for `key`, merging it with
+    /// a message already buffered for the same key. The node is left
+    /// unchanged if the merge fails, see [Message::merge_with_upper].
+    pub fn insert_message(&mut self, key: CowBytes, info: KeyInfo, message: Message) -> Result<()> {
+        let idx = self.child_index(&key);
+        let child = &mut self.children[idx];
+        let merged = match child.messages.get(&key) {
+            Some((lower_info, lower)) => {
+                (lower_info.clone().merge_with_upper(info), lower.clone().merge_with_upper(message)?)
+            }
+            None => (info, message),
+        };
+        child.storage_preference.upgrade(*merged.0.storage_preference());
+        child.messages.insert(key, merged);
+        Ok(())
+    }
+
+    /// Total number of buffered messages.
+    pub fn buffered_messages(&self) -> usize {
+        self.children.iter().map(|child| child.messages.len()).sum()
+    }
+
+    /// Returns the fastest storage preference of all children and buffered
+    /// messages. Children with an unknown preference are recomputed from
+    /// `child_preference`, which returns the preference of the child node at
+    /// the given location, and cached again.
+    ///
+    /// Modifications racing with the recomputation of a child have to
+    /// invalidate it again afterwards.
+    pub fn storage_preference(
+        &self,
+        mut child_preference: impl FnMut(&ChildPointer) -> StoragePreference,
+    ) -> StoragePreference {
+        let pref = AtomicStoragePreference::known(StoragePreference::NONE);
+        for child in self.children.iter() {
+            if child.storage_preference.as_option().is_some() {
+                pref.upgrade_atomic(&child.storage_preference);
+                continue;
+            }
+            let messages = KeyInfo::merge_all(child.messages.values().map(|(info, _)| info));
+            let mut fresh = child_preference(&child.pointer);
+            fresh.upgrade(*messages.storage_preference());
+            child.storage_preference.set(fresh);
+            pref.upgrade(fresh);
+        }
+        pref.unwrap_or_none()
+    }
+
+    /// Marks the preference of child `idx` as unknown after it was modified
+    /// in a way which may make it slower, e.g. by removing entries.
+    pub fn invalidate_child(&self, idx: usize) {
+        self.children[idx].storage_preference.invalidate();
+    }
+}
+
+impl ArchivedInternalNode {
+    /// Returns the index of the child responsible for `key`.
+    pub fn child_index(&self, key: &[u8]) -> usize {
+        child_index_by(self.pivots.len(), |i| self.pivots[i].as_ref().cmp(key))
+    }
+
+    /// Returns whether all buffered slices stay within their data.
+    pub fn slices_in_bounds(&self) -> bool {
+        self.children
+            .iter()
+            .all(|child| child.messages.iter().all(|entry| entry.value.1.is_in_bounds()))
+    }
+}
+
+/// Validates `bytes` as an archived `InternalNode`, including the bounds of
+/// all buffered slices.
+pub fn rkyv_check(bytes: &[u8]) -> Result<&ArchivedInternalNode> {
+    let archived = codec::rkyv_check_value::<InternalNode>(bytes)?;
+    if !archived.slices_in_bounds() {
+        return Err(Error::Validate { context: "buffered slice is out of bounds".to_string() });
+    }
+    Ok(archived)
+}
+
+/// Builds an internal node with `children` children, each buffering
+/// `messages_per_child` messages. The message of the key with number `n` is
+/// `message(n)`.
+pub fn sample_internal_node(
+    children: u32,
+    messages_per_child: u32,
+    message: impl Fn(u32) -> Message,
+) -> InternalNode {
+    let keys: BTreeMap<CowBytes, u32> = (1..=children * messages_per_child)
+        .map(|number| (CowBytes::from(number.to_string().as_bytes()), number))
+        .collect();
+    let keys: Vec<(CowBytes, u32)> = keys.into_iter().collect();
+
+    let mut node = InternalNode { level: 1, pivots: Vec::new(), children: Vec::new() };
+    for (idx, chunk) in keys.chunks(messages_per_child.max(1) as usize).enumerate() {
+        if let Some(last) = node.children.last().and_then(|child| child.messages.keys().next_back()) {
+            node.pivots.push(last.clone());
+        }
+        node.children.push(ChildBuffer {
+            pointer: ChildPointer { offset: idx as u64 * 4 * 1024 * 1024, size: 4 * 1024 * 1024 },
+            storage_preference: AtomicStoragePreference::unknown(),
+            messages: chunk
+                .iter()
+                .map(|(key, number)| {
+                    (key.clone(), (KeyInfo::new(StoragePreference::new(1)), message(*number)))
+                })
+                .collect(),
+        });
+    }
+    node
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::sample_entries;
+    use crate::cow_bytes::SlicedCowBytes;
+    use crate::message::{sample_message, Upsert};
+    use crate::Type_A;
+    use std::thread;
+
+    #[test]
+    fn round_trip() {
+        let node = sample_internal_node(8, 16, |number| sample_message(number, 32));
+        assert_eq!(node.pivots.len() + 1, node.children.len());
+        assert_eq!(node.buffered_messages(), 8 * 16);
+
+        let bytes = codec::rkyv_serialize_value(&node).unwrap();
+        let archived = rkyv_check(&bytes).unwrap();
+        assert_eq!(codec::rkyv_deserialize_value::<InternalNode>(archived).unwrap(), node);
+
+        let bytes = codec::bincode_serialize_value(&node).unwrap();
+        assert_eq!(codec::bincode_deserialize_value::<InternalNode>(&bytes).unwrap(), node);
+    }
+
+    #[test]
+    fn child_index_matches_pivots() {
+        let node = sample_internal_node(8, 16, |number| sample_message(number, 32));
+        let bytes = codec::rkyv_serialize_value(&node).unwrap();
+        let archived = rkyv_check(&bytes).unwrap();
+
+        for (idx, child) in node.children.iter().enumerate() {
+            for key in child.messages.keys() {
+                assert_eq!(node.child_index(key), idx);
+                assert_eq!(archived.child_index(key), idx);
+            }
+        }
+        assert_eq!(node.child_index(b""), 0);
+        assert_eq!(node.child_index(b"\xff"), node.children.len() - 1);
+    }
+
+    #[test]
+    fn insert_upgrades_child_preference() {
+        let mut node = sample_internal_node(4, 4, |number| sample_message(number, 8));
+        for child in node.children.iter() {
+            child.storage_preference.set(StoragePreference::SLOWEST);
+        }
+        let info = KeyInfo::new(StoragePreference::FAST);
+        node.insert_message(CowBytes::from(&b"0"[..]), info, Message::Delete).unwrap();
+
+        assert_eq!(node.children[0].storage_preference.as_option(), Some(StoragePreference::FAST));
+        assert_eq!(node.children[1].storage_preference.as_option(), Some(StoragePreference::SLOWEST));
+    }
+
+    #[test]
+    fn insert_merges_with_buffered_message() {
+        let mut node = sample_internal_node(4, 4, |_| Message::Delete);
+        let key = CowBytes::from(&b"1"[..]);
+        let info = KeyInfo::new(StoragePreference::FAST);
+        let value = SlicedCowBytes::from(CowBytes::from(&b"ab"[..]));
+        let upsert = Message::Upsert(vec![Upsert { offset: 1, data: value }]);
+        node.insert_message(key.clone(), info.clone(), upsert).unwrap();
+
+        let idx = node.child_index(&key);
+        let (merged_info, merged) = node.children[idx].messages[&key].clone();
+        assert_eq!(merged_info, info);
+        assert_eq!(merged, Message::Insert(SlicedCowBytes::from(CowBytes::from(&b"\0ab"[..]))));
+        assert_eq!(node.buffered_messages(), 4 * 4);
+
+        let value = SlicedCowBytes::from(CowBytes::from(&b"ab"[..]));
+        let oversized = Message::Upsert(vec![Upsert { offset: u32::MAX, data: value }]);
+        node.insert_message(key.clone(), info, Message::Delete).unwrap();
+        assert!(node.insert_message(key.clone(), KeyInfo::default(), oversized).is_err());
+        assert_eq!(node.children[idx].messages[&key], (merged_info, Message::Delete));
+    }
+
+    fn sample_leaves(count: usize) -> Vec<Type_A> {
+        (0..count)
+            .map(|_| {
+                let mut entries = sample_entries(8, 8);
+                for (_, (info, _)) in entries.iter_mut() {
+                    *info = KeyInfo::new(StoragePreference::SLOWEST);
+                }
+                Type_A { entries }
+            })
+            .collect()
+    }
+
+    fn leaf_preference(leaves: &[Type_A]) -> impl Fn(&ChildPointer) -> StoragePreference + '_ {
+        |pointer| leaves[pointer.offset as usize].storage_preference()
+    }
+
+    /// An internal node over `leaves` with `leaves.len()` children at offsets
+    /// `0..`, all of them unknown.
+    fn node_over(leaves: &[Type_A]) -> InternalNode {
+        let mut node = sample_internal_node(leaves.len() as u32, 2, |_| Message::Delete);
+        for (idx, child) in node.children.iter_mut().enumerate() {
+            child.pointer.offset = idx as u64;
+            for (info, _) in child.messages.values_mut() {
+                *info = KeyInfo::new(StoragePreference::NONE);
+            }
+        }
+        node
+    }
+
+    #[test]
+    fn preference_propagates_bottom_up() {
+        let mut leaves = sample_leaves(4);
+        let node = node_over(&leaves);
+        assert_eq!(node.storage_preference(leaf_preference(&leaves)), StoragePreference::SLOWEST);
+        assert!(node.children.iter().all(|child| child.storage_preference.as_option().is_some()));
+
+        let (_, (info, _)) = leaves[2].entries.iter_mut().next().unwrap();
+        *info = KeyInfo::new(StoragePreference::FAST);
+        // Still cached.
+        assert_eq!(node.storage_preference(leaf_preference(&leaves)), StoragePreference::SLOWEST);
+        node.invalidate_child(2);
+        assert_eq!(node.storage_preference(leaf_preference(&leaves)), StoragePreference::FAST);
+
+        // Removing the fast entry only shows up after invalidating.
+        leaves[2] = sample_leaves(1).remove(0);
+        assert_eq!(node.storage_preference(leaf_preference(&leaves)), StoragePreference::FAST);
+        node.invalidate_child(2);
+        assert_eq!(node.storage_preference(leaf_preference(&leaves)), StoragePreference::SLOWEST);
+    }
+
+    #[test]
+    fn buffered_messages_count_towards_preference() {
+        let leaves = sample_leaves(4);
+        let mut node = node_over(&leaves);
+        let info = KeyInfo::new(StoragePreference::SLOW);
+        node.insert_message(CowBytes::from(&b"1"[..]), info, Message::Delete).unwrap();
+        assert_eq!(node.storage_preference(leaf_preference(&leaves)), StoragePreference::SLOW);
+
+        // Known children are upgraded in place.
+        let info = KeyInfo::new(StoragePreference::FASTEST);
+        node.insert_message(CowBytes::from(&b"9"[..]), info, Message::Delete).unwrap();
+        assert_eq!(node.storage_preference(|_| unreachable!()), StoragePreference::FASTEST);
+    }
+
+    #[test]
+    fn concurrent_upgrades_keep_the_fastest() {
+        let pref = AtomicStoragePreference::known(StoragePreference::NONE);
+        let children: Vec<_> = (0..8).map(|_| AtomicStoragePreference::known(StoragePreference::SLOWEST)).collect();
+        thread::scope(|scope| {
+            for (idx, child) in children.iter().enumerate() {
+                let pref = &pref;
+                scope.spawn(move || {
+                    for round in 0..1000 {
+                        let class = (idx + round) % 4;
+                        child.upgrade(StoragePreference::new(class.max(idx % 4) as u8));
+                        pref.upgrade_atomic(child);
+                    }
+                });
+            }
+        });
+        for (idx, child) in children.iter().enumerate() {
+            assert_eq!(child.as_option(), Some(StoragePreference::new((idx % 4) as u8)));
+        }
+        assert_eq!(pref.as_option(), Some(StoragePreference::FASTEST));
+
+        // Upgrading an unknown preference keeps it unknown.
+        let unknown = AtomicStoragePreference::unknown();
+        thread::scope(|scope| {
+            for child in children.iter() {
+                scope.spawn(|| unknown.upgrade_atomic(child));
+            }
+        });
+        assert_eq!(unknown.as_option(), None);
+    }
+
+    #[test]
+    fn concurrent_recomputation() {
+        let mut leaves = sample_leaves(16);
+        let (_, (info, _)) = leaves[11].entries.iter_mut().next().unwrap();
+        *info = KeyInfo::new(StoragePreference::FAST);
+        let node = node_over(&leaves);
+
+        thread::scope(|scope| {
+            for thread in 0..8 {
+                let (node, leaves) = (&node, &leaves);
+                scope.spawn(move || {
+                    for round in 0..200 {
+                        if (thread + round) % 3 == 0 {
+                            node.invalidate_child((thread + round) % leaves.len());
+                        }
+                        assert_eq!(node.storage_preference(leaf_preference(leaves)), StoragePreference::FAST);
+                    }
+                });
+            }
+        });
+        assert_eq!(node.storage_preference(|_| StoragePreference::SLOWEST), StoragePreference::FAST);
+    }
+}
@@ -0,0 +1,360 @@
+//! This module provides `KeyInfo`, the meta information stored with every
+//! key.
+//!
+//! Besides the storage preference, a key info carries optional fields: a time
+//! to live, a sequence number and application defined flags. Absent fields
+//! are encoded as sentinel values instead of `Option`s, so the bincode
+//! encoding keeps a static size of [KeyInfo::BINCODE_SIZE] bytes.
+//!
+//! Before the optional fields were added, a key info consisted of its storage
+//! preference only. [LegacyKeyInfo] decodes that one byte encoding.
+
+use crate::error::{Codec, Error, Result};
+use crate::size::StaticSize;
+use crate::storage_preference::StoragePreference;
+
+use std::{convert::TryInto, mem, pin::Pin, time::Duration};
+
+// Sentinels of the optional fields. Zero bytes are avoided, so that a
+// truncated rkyv buffer ending in a key info is not taken for an empty node.
+const NO_TTL: u64 = u64::MAX;
+const NO_SEQUENCE: u64 = u64::MAX;
+
+fn decode_preference(byte: u8) -> Result<StoragePreference> {
+    StoragePreference::try_from(byte).map_err(Error::deserialize(Codec::Bincode))
+}
+
+/// Additional information for a single entry. Concerns meta information like
+/// the desired storage level of a key.
+///
+/// Build one with [KeyInfo::new] and the `with_*` methods:
+///
+/// ```ignore
+/// let info = KeyInfo::new(StoragePreference::FAST).with_sequence(42).with_ttl(Duration::from_secs(60));
+/// ```
+#[derive(
+    Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize,
+)]
+#[archive(check_bytes)]
+pub struct KeyInfo {
+    storage_preference: StoragePreference,
+    flags: u8,
+    // Whole seconds, `NO_TTL` if the key does not expire.
+    ttl_secs: u64,
+    // `NO_SEQUENCE` if the key has no sequence number.
+    sequence: u64,
+}
+
+impl KeyInfo {
+    /// Size of the bincode encoding.
+    pub const BINCODE_SIZE: usize = 2 + 2 * mem::size_of::<u64>();
+
+    /// A key info with the given storage preference and no optional fields.
+    pub const fn new(storage_preference: StoragePreference) -> Self {
+        KeyInfo { storage_preference, flags: 0, ttl_secs: NO_TTL, sequence: NO_SEQUENCE }
+    }
+
+    /// The desired storage class of the key.
+    pub fn storage_preference(&self) -> &StoragePreference {
+        &self.storage_preference
+    }
+
+    /// Replaces the storage preference.
+    pub fn with_storage_preference(mut self, pref: StoragePreference) -> Self {
+        self.set_storage_preference(pref);
+        self
+    }
+
+    /// See [KeyInfo::with_storage_preference].
+    pub fn set_storage_preference(&mut self, pref: StoragePreference) {
+        self.storage_preference = pref;
+    }
+
+    /// Returns the time to live, in whole seconds.
+    pub fn ttl(&self) -> Option<Duration> {
+        (self.ttl_secs != NO_TTL).then(|| Duration::from_secs(self.ttl_secs))
+    }
+
+    /// Sets the time to live, rounded up to whole seconds and capped at
+    /// `u64::MAX - 1` seconds. `None` removes it.
+    pub fn with_ttl(mut self, ttl: impl Into<Option<Duration>>) -> Self {
+        self.set_ttl(ttl);
+        self
+    }
+
+    /// See [KeyInfo::with_ttl].
+    pub fn set_ttl(&mut self, ttl: impl Into<Option<Duration>>) {
+        self.ttl_secs = ttl
+            .into()
+            .map_or(NO_TTL, |ttl| ttl.as_secs().saturating_add((ttl.subsec_nanos() > 0) as u64).min(NO_TTL - 1));
+    }
+
+    /// Returns the sequence number, if one was set.
+    pub fn sequence(&self) -> Option<u64> {
+        (self.sequence != NO_SEQUENCE).then_some(self.sequence)
+    }
+
+    /// Sets the sequence number. Panics if `sequence` is `u64::MAX`, which is
+    /// reserved for the absence of a sequence number.
+    pub fn with_sequence(mut self, sequence: impl Into<Option<u64>>) -> Self {
+        self.set_sequence(sequence);
+        self
+    }
+
+    /// See [KeyInfo::with_sequence].
+    pub fn set_sequence(&mut self, sequence: impl Into<Option<u64>>) {
+        let sequence = sequence.into();
+        assert!(sequence != Some(NO_SEQUENCE), "sequence number u64::MAX is reserved");
+        self.sequence = sequence.unwrap_or(NO_SEQUENCE);
+    }
+
+    /// Application defined flags, 0 by default.
+    pub fn flags(&self) -> u8 {
+        self.flags
+    }
+
+    /// Replaces the application defined flags.
+    pub fn with_flags(mut self, flags: u8) -> Self {
+        self.set_flags(flags);
+        self
+    }
+
+    /// See [KeyInfo::with_flags].
+    pub fn set_flags(&mut self, flags: u8) {
+        self.flags = flags;
+    }
+
+    /// Merges the info of an older message with the info of the newer message
+    /// `upper`: the faster storage preference and the larger sequence number
+    /// win, the time to live of `upper` replaces the older one if present and
+    /// the flags are combined.
+    pub fn merge_with_upper(self, upper: KeyInfo) -> KeyInfo {
+        KeyInfo {
+            storage_preference: StoragePreference::choose_faster(self.storage_preference, upper.storage_preference),
+            flags: self.flags | upper.flags,
+            ttl_secs: if upper.ttl_secs != NO_TTL { upper.ttl_secs } else { self.ttl_secs },
+            sequence: match (self.sequence(), upper.sequence()) {
+                (Some(lower), Some(upper)) => lower.max(upper),
+                (lower, upper) => upper.or(lower).unwrap_or(NO_SEQUENCE),
+            },
+        }
+    }
+
+    /// Merges `infos` into the info of a node holding all of them, which
+    /// prefers the fastest of their storage preferences.
+    pub fn merge_all<'a>(infos: impl IntoIterator<Item = &'a KeyInfo>) -> KeyInfo {
+        infos.into_iter().cloned().fold(KeyInfo::default(), KeyInfo::merge_with_upper)
+    }
+
+    /// Decodes a key info from its bincode encoding, accepting the current
+    /// and the legacy one byte encoding.
+    pub fn bincode_decode(bytes: &[u8]) -> Result<KeyInfo> {
+        match bytes.len() {
+            LegacyKeyInfo::BINCODE_SIZE => Ok(KeyInfo::new(decode_preference(bytes[0])?)),
+            KeyInfo::BINCODE_SIZE => {
+                Ok(KeyInfo {
+                    storage_preference: decode_preference(bytes[0])?,
+                    flags: bytes[1],
+                    ttl_secs: u64::from_le_bytes(bytes[2..10].try_into().unwrap()),
+                    sequence: u64::from_le_bytes(bytes[10..18].try_into().unwrap()),
+                })
+            }
+            len => Err(Error::Deserialize { codec: Codec::Bincode, message: format!("key info has {} bytes", len) }),
+        }
+    }
+
+    /// Returns the bincode encoding, as `bincode::serialize` would.
+    pub fn bincode_encode(&self) -> [u8; KeyInfo::BINCODE_SIZE] {
+        let mut bytes = [0; KeyInfo::BINCODE_SIZE];
+        bytes[0] = self.storage_preference.as_u8();
+        bytes[1] = self.flags;
+        bytes[2..10].copy_from_slice(&self.ttl_secs.to_le_bytes());
+        bytes[10..18].copy_from_slice(&self.sequence.to_le_bytes());
+        bytes
+    }
+}
+
+impl Default for KeyInfo {
+    /// No storage preference and no optional fields.
+    fn default() -> Self {
+        KeyInfo::new(StoragePreference::NONE)
+    }
+}
+
+impl StaticSize for KeyInfo {
+    fn static_size() -> usize {
+        KeyInfo::BINCODE_SIZE
+    }
+}
+
+impl From<&ArchivedKeyInfo> for KeyInfo {
+    fn from(x: &ArchivedKeyInfo) -> Self {
+        KeyInfo {
+            storage_preference: (&x.storage_preference).into(),
+            flags: x.flags,
+            ttl_secs: rkyv::from_archived!(x.ttl_secs),
+            sequence: rkyv::from_archived!(x.sequence),
+        }
+    }
+}
+
+impl ArchivedKeyInfo {
+    /// See [KeyInfo::storage_preference].
+    pub fn storage_preference(&self) -> StoragePreference {
+        (&self.storage_preference).into()
+    }
+
+    /// See [KeyInfo::ttl].
+    pub fn ttl(&self) -> Option<Duration> {
+        KeyInfo::from(self).ttl()
+    }
+
+    /// See [KeyInfo::sequence].
+    pub fn sequence(&self) -> Option<u64> {
+        KeyInfo::from(self).sequence()
+    }
+
+    /// See [KeyInfo::flags].
+    pub fn flags(&self) -> u8 {
+        self.flags
+    }
+
+    /// Overwrites the archived preference in place.
+    pub fn set_storage_preference(self: Pin<&mut Self>, pref: StoragePreference) {
+        // Projecting to a field does not move the key info.
+        unsafe { self.map_unchecked_mut(|info| &mut info.storage_preference) }.set(pref);
+    }
+}
+
+/// The encoding of a [KeyInfo] before the optional fields were added, which
+/// is one byte in both bincode and rkyv.
+#[derive(
+    Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize,
+)]
+#[archive(check_bytes)]
+pub struct LegacyKeyInfo {
+    pub storage_preference: StoragePreference,
+}
+
+impl LegacyKeyInfo {
+    /// Size of the bincode encoding.
+    pub const BINCODE_SIZE: usize = mem::size_of::<StoragePreference>();
+}
+
+impl StaticSize for LegacyKeyInfo {
+    fn static_size() -> usize {
+        LegacyKeyInfo::BINCODE_SIZE
+    }
+}
+
+impl From<LegacyKeyInfo> for KeyInfo {
+    fn from(legacy: LegacyKeyInfo) -> Self {
+        KeyInfo::new(legacy.storage_preference)
+    }
+}
+
+impl From<&ArchivedLegacyKeyInfo> for KeyInfo {
+    fn from(legacy: &ArchivedLegacyKeyInfo) -> Self {
+        KeyInfo::new((&legacy.storage_preference).into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_info() -> KeyInfo {
+        KeyInfo::new(StoragePreference::FAST).with_flags(0b101).with_ttl(Duration::from_millis(1500)).with_sequence(7)
+    }
+
+    #[test]
+    fn builders_and_accessors() {
+        let info = sample_info();
+        assert_eq!(*info.storage_preference(), StoragePreference::FAST);
+        assert_eq!(info.flags(), 0b101);
+        assert_eq!(info.ttl(), Some(Duration::from_secs(2)));
+        assert_eq!(info.sequence(), Some(7));
+
+        let cleared = info.with_ttl(None).with_sequence(None).with_flags(0);
+        assert_eq!(cleared, KeyInfo::new(StoragePreference::FAST));
+        assert_eq!(cleared.ttl(), None);
+        assert_eq!(cleared.sequence(), None);
+        assert_eq!(KeyInfo::default().with_ttl(Duration::ZERO).ttl(), Some(Duration::ZERO));
+        assert_eq!(KeyInfo::default().with_ttl(Duration::MAX).ttl(), Some(Duration::from_secs(u64::MAX - 1)));
+        assert_eq!(KeyInfo::default().with_sequence(0).sequence(), Some(0));
+    }
+
+    #[test]
+    #[should_panic]
+    fn reserved_sequence_is_rejected() {
+        KeyInfo::default().with_sequence(u64::MAX);
+    }
+
+    #[test]
+    fn merge_prefers_upper() {
+        let lower = sample_info();
+        let upper = KeyInfo::new(StoragePreference::SLOW).with_flags(0b010).with_sequence(3);
+        let merged = lower.clone().merge_with_upper(upper.clone());
+        assert_eq!(*merged.storage_preference(), StoragePreference::FAST);
+        assert_eq!(merged.flags(), 0b111);
+        assert_eq!(merged.ttl(), Some(Duration::from_secs(2)));
+        assert_eq!(merged.sequence(), Some(7));
+
+        let upper = upper.with_ttl(Duration::from_secs(5)).with_sequence(9);
+        let merged = lower.merge_with_upper(upper);
+        assert_eq!(merged.ttl(), Some(Duration::from_secs(5)));
+        assert_eq!(merged.sequence(), Some(9));
+        assert_eq!(KeyInfo::merge_all([]), KeyInfo::default());
+    }
+
+    #[test]
+    fn encoding_has_static_size() {
+        for info in [KeyInfo::default(), sample_info()] {
+            let bytes = bincode::serialize(&info).unwrap();
+            assert_eq!(bytes.len(), KeyInfo::static_size());
+            assert_eq!(bytes, info.bincode_encode());
+            assert_eq!(KeyInfo::bincode_decode(&bytes).unwrap(), info);
+
+            let bytes = rkyv::to_bytes::<_, 256>(&info).unwrap();
+            let archived = rkyv::check_archived_root::<KeyInfo>(&bytes).unwrap();
+            assert_eq!(KeyInfo::from(archived), info);
+            assert_eq!(archived.storage_preference(), *info.storage_preference());
+            let fields = (archived.flags(), archived.ttl(), archived.sequence());
+            assert_eq!(fields, (info.flags(), info.ttl(), info.sequence()));
+        }
+    }
+
+    #[test]
+    fn legacy_encoding_decodes() {
+        let legacy = LegacyKeyInfo { storage_preference: StoragePreference::SLOW };
+        let bytes = bincode::serialize(&legacy).unwrap();
+        assert_eq!(bytes, [2]);
+        assert_eq!(KeyInfo::bincode_decode(&bytes).unwrap(), KeyInfo::new(StoragePreference::SLOW));
+
+        let bytes = rkyv::to_bytes::<_, 256>(&legacy).unwrap();
+        let archived = rkyv::check_archived_root::<LegacyKeyInfo>(&bytes).unwrap();
+        assert_eq!(KeyInfo::from(archived), KeyInfo::new(StoragePreference::SLOW));
+
+        for bytes in [vec![0; 0], vec![0; 2], vec![0; 17], vec![0; 19], vec![255], vec![255; 18]] {
+            assert_eq!(KeyInfo::bincode_decode(&bytes).unwrap_err().phase(), "deserialize");
+        }
+    }
+
+    #[test]
+    fn invalid_preference_is_rejected() {
+        let mut bytes = bincode::serialize(&sample_info()).unwrap();
+        bytes[0] = 255;
+        assert!(bincode::deserialize::<KeyInfo>(&bytes).is_err());
+        assert_eq!(KeyInfo::bincode_decode(&bytes).unwrap_err().phase(), "deserialize");
+
+        // Locate the archived preference by the only byte two archives differ in.
+        let fast = rkyv::to_bytes::<_, 256>(&sample_info()).unwrap();
+        let slow = rkyv::to_bytes::<_, 256>(&sample_info().with_storage_preference(StoragePreference::SLOW)).unwrap();
+        let position = fast.iter().zip(slow.iter()).position(|(a, b)| a != b).unwrap();
+        let mut bytes = fast.clone();
+        bytes[position] = 255;
+        assert!(rkyv::check_archived_root::<KeyInfo>(&bytes).is_err());
+        bytes[position] = StoragePreference::NONE.as_u8();
+        assert_eq!(rkyv::check_archived_root::<KeyInfo>(&bytes).unwrap().storage_preference(), StoragePreference::NONE);
+    }
+}
@@ -0,0 +1,432 @@
+//! This module provides splitting and merging of leaves (`Type_A`/`Type_B`)
+//! driven by their predicted encoded size.
+//!
+//! `Type_B` implements [Size] with its exact bincode size. `Type_A` predicts
+//! an upper bound of its rkyv size instead, as the alignment padding between
+//! the archived parts depends on the order in which they are written. Note
+//! that rkyv archives the whole buffer behind a `SlicedCowBytes`, while
+//! bincode only writes the slice.
+//!
+//! Both leaves report their effective storage preference, the fastest
+//! preference of their entries, which [crate::internal::InternalNode]
+//! propagates upwards.
+//!
+//! Leaves written before [KeyInfo] grew its optional fields are read with
+//! [LegacyLeaf].
+//!
+//! The archived leaf additionally provides access to the entries of a key
+//! range. The entries are archived in key order with their values stored out
+//! of line, so a range is found by binary search and iterated without reading
+//! any value bytes.
+
+use crate::codec;
+use crate::cow_bytes::{ArchivedCowBytes, CowBytes, SlicedCowBytes};
+use crate::error;
+use crate::key_info::{KeyInfo, LegacyKeyInfo};
+use crate::size::{Size, StaticSize};
+use crate::storage_preference::StoragePreference;
+use crate::{ArchivedType_A, Type_A, Type_B};
+
+use rkyv::{collections::util::Entry, Archived};
+use std::{
+    collections::BTreeMap,
+    mem,
+    ops::{Bound, RangeBounds},
+    slice,
+};
+
+type Entries = BTreeMap<CowBytes, (KeyInfo, SlicedCowBytes)>;
+
+/// An entry of an archived leaf.
+pub type ArchivedEntry = Entry<ArchivedCowBytes, Archived<(KeyInfo, SlicedCowBytes)>>;
+
+/// Predicted size of one entry in the bincode encoding.
+fn bincode_entry_size(key: &CowBytes, (_, value): &(KeyInfo, SlicedCowBytes)) -> usize {
+    key.size() + KeyInfo::static_size() + value.size()
+}
+
+/// Upper bound of the size of one entry in the rkyv encoding.
+fn rkyv_entry_size(key: &CowBytes, (_, value): &(KeyInfo, SlicedCowBytes)) -> usize {
+    mem::size_of::<ArchivedEntry>() + key.len() + value.data.len()
+}
+
+// Size of the map length in bincode.
+const BINCODE_HEADER_SIZE: usize = 8;
+
+// Root plus the padding in front of the root and the entry array.
+const RKYV_HEADER_SIZE: usize = mem::size_of::<ArchivedType_A>() + 2 * (mem::align_of::<ArchivedType_A>() - 1);
+
+/// Moves entries from the end of `left` into a new map until the
+/// remaining entries fit into `target_size`, keeping at least one entry in
+/// each half. Returns the pivot (the largest key of `left`) and the right
+/// half, or `None` if `left` has fewer than two entries or the right half
+/// does not fit into `target_size` either. A half of a single entry may
+/// exceed `target_size`. `left` is unchanged if `None` is returned.
+fn split_entries(
+    left: &mut Entries,
+    target_size: usize,
+    header_size: usize,
+    entry_size: fn(&CowBytes, &(KeyInfo, SlicedCowBytes)) -> usize,
+) -> Option<(CowBytes, Entries)> {
+    if left.len() < 2 {
+        return None;
+    }
+    let mut size = header_size;
+    let split_key = left
+        .iter()
+        .enumerate()
+        .find(|&(idx, (key, value))| {
+            size += entry_size(key, value);
+            idx > 0 && size > target_size
+        })
+        .map_or_else(|| left.keys().next_back().unwrap().clone(), |(_, (key, _))| key.clone());
+    let mut right = left.split_off(&split_key);
+    let right_size = header_size + right.iter().map(|(key, value)| entry_size(key, value)).sum::<usize>();
+    if right.len() > 1 && right_size > target_size {
+        left.append(&mut right);
+        return None;
+    }
+    let pivot = left.keys().next_back().unwrap().clone();
+    Some((pivot, right))
+}
+
+/// Moves all entries of `right` into `left`. Entries present in both are
+/// taken from `right`, with their key infos merged.
+fn merge_entries(left: &mut Entries, right: Entries) {
+    for (key, (info, value)) in right {
+        let info = match left.remove(&key) {
+            Some((lower, _)) => lower.merge_with_upper(info),
+            None => info,
+        };
+        left.insert(key, (info, value));
+    }
+}
+
+/// Predicted size of the union of two entry maps. Entries present in both
+/// are counted twice, so the result may be too large, but never too small.
+fn merged_size(
+    left: &Entries,
+    right: &Entries,
+    entry_size: fn(&CowBytes, &(KeyInfo, SlicedCowBytes)) -> usize,
+) -> usize {
+    left.iter().chain(right.iter()).map(|(key, value)| entry_size(key, value)).sum()
+}
+
+impl Size for Type_B {
+    fn size(&self) -> usize {
+        BINCODE_HEADER_SIZE + self.entries.iter().map(|(key, value)| bincode_entry_size(key, value)).sum::<usize>()
+    }
+
+    fn actual_size(&mut self) -> Option<usize> {
+        bincode::serialized_size(self).ok().map(|size| size as usize)
+    }
+}
+
+impl Type_B {
+    /// Splits the leaf so that both halves are encoded in at most
+    /// `target_size` bytes, unless they consist of a single entry, and
+    /// returns them together with the pivot, the largest key of the left
+    /// half. Returns `Err(self)` if the leaf has fewer than two entries or
+    /// does not fit into two halves of `target_size`.
+    pub fn split(mut self, target_size: usize) -> Result<(Type_B, CowBytes, Type_B), Type_B> {
+        match split_entries(&mut self.entries, target_size, BINCODE_HEADER_SIZE, bincode_entry_size) {
+            Some((pivot, right)) => Ok((self, pivot, Type_B { entries: right })),
+            None => Err(self),
+        }
+    }
+
+    /// Merges the right sibling `right` into this leaf if the result is
+    /// encoded in at most `max_size` bytes, and returns `right` otherwise.
+    pub fn merge(&mut self, right: Type_B, max_size: usize) -> Result<(), Type_B> {
+        if BINCODE_HEADER_SIZE + merged_size(&self.entries, &right.entries, bincode_entry_size) > max_size {
+            return Err(right);
+        }
+        merge_entries(&mut self.entries, right.entries);
+        Ok(())
+    }
+
+    /// The fastest storage preference of all entries, or
+    /// [StoragePreference::NONE] if the leaf is empty.
+    pub fn storage_preference(&self) -> StoragePreference {
+        *KeyInfo::merge_all(self.entries.values().map(|(info, _)| info)).storage_preference()
+    }
+}
+
+impl Type_A {
+    /// Upper bound of the size of the rkyv encoding of this leaf.
+    pub fn rkyv_size(&self) -> usize {
+        RKYV_HEADER_SIZE + self.entries.iter().map(|(key, value)| rkyv_entry_size(key, value)).sum::<usize>()
+    }
+
+    /// Splits the leaf so that both halves are encoded in at most
+    /// `target_size` bytes, unless they consist of a single entry, and
+    /// returns them together with the pivot, the largest key of the left
+    /// half. Returns `Err(self)` if the leaf has fewer than two entries or
+    /// does not fit into two halves of `target_size`.
+    pub fn split(mut self, target_size: usize) -> Result<(Type_A, CowBytes, Type_A), Type_A> {
+        match split_entries(&mut self.entries, target_size, RKYV_HEADER_SIZE, rkyv_entry_size) {
+            Some((pivot, right)) => Ok((self, pivot, Type_A { entries: right })),
+            None => Err(self),
+        }
+    }
+
+    /// Merges the right sibling `right` into this leaf if the result is
+    /// encoded in at most `max_size` bytes, and returns `right` otherwise.
+    pub fn merge(&mut self, right: Type_A, max_size: usize) -> Result<(), Type_A> {
+        if RKYV_HEADER_SIZE + merged_size(&self.entries, &right.entries, rkyv_entry_size) > max_size {
+            return Err(right);
+        }
+        merge_entries(&mut self.entries, right.entries);
+        Ok(())
+    }
+
+    /// The fastest storage preference of all entries, or
+    /// [StoragePreference::NONE] if the leaf is empty.
+    pub fn storage_preference(&self) -> StoragePreference {
+        *KeyInfo::merge_all(self.entries.values().map(|(info, _)| info)).storage_preference()
+    }
+}
+
+impl ArchivedType_A {
+    /// Returns the entries whose keys lie within `range`, in key order.
+    pub fn range<K: AsRef<[u8]> + ?Sized, R: RangeBounds<K>>(&self, range: R) -> slice::Iter<'_, ArchivedEntry> {
+        let entries = &self.entries[..];
+        let position = |bound: Bound<&K>, unbounded: usize, inclusive: bool| match bound {
+            Bound::Included(key) if inclusive => entries.partition_point(|entry| entry.key.as_ref() <= key.as_ref()),
+            Bound::Included(key) => entries.partition_point(|entry| entry.key.as_ref() < key.as_ref()),
+            Bound::Excluded(key) if inclusive => entries.partition_point(|entry| entry.key.as_ref() < key.as_ref()),
+            Bound::Excluded(key) => entries.partition_point(|entry| entry.key.as_ref() <= key.as_ref()),
+            Bound::Unbounded => unbounded,
+        };
+        let start = position(range.start_bound(), 0, false);
+        let end = position(range.end_bound(), entries.len(), true);
+        entries[start..end.max(start)].iter()
+    }
+
+    /// Returns the keys within `range`, in order.
+    pub fn keys<K: AsRef<[u8]> + ?Sized, R: RangeBounds<K>>(&self, range: R) -> impl Iterator<Item = &[u8]> {
+        self.range(range).map(|entry| entry.key.as_ref())
+    }
+}
+
+/// A leaf as encoded before [KeyInfo] grew its optional fields. Its bincode
+/// encoding matches the one of an old `Type_B` and its rkyv encoding the one
+/// of an old `Type_A`.
+#[derive(
+    Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize,
+)]
+#[archive(check_bytes)]
+pub struct LegacyLeaf {
+    #[with(rkyv::with::AsVec)]
+    pub entries: BTreeMap<CowBytes, (LegacyKeyInfo, SlicedCowBytes)>,
+}
+
+impl LegacyLeaf {
+    /// Converts the entries to the current key info.
+    pub fn into_entries(self) -> Entries {
+        self.entries.into_iter().map(|(key, (info, value))| (key, (info.into(), value))).collect()
+    }
+
+    /// Decodes an old bincode encoded `Type_B`.
+    pub fn bincode_deserialize(bytes: &[u8]) -> error::Result<Type_B> {
+        let legacy: LegacyLeaf = codec::bincode_deserialize_value(bytes)?;
+        Ok(Type_B { entries: legacy.into_entries() })
+    }
+
+    /// Validates and decodes an old rkyv encoded `Type_A`.
+    pub fn rkyv_deserialize(bytes: &[u8]) -> error::Result<Type_A> {
+        let archived = codec::rkyv_check_value::<LegacyLeaf>(bytes)?;
+        codec::check_slices(archived.entries.iter().map(|entry| (entry.key.as_ref(), &entry.value.1)))?;
+        let legacy: LegacyLeaf = codec::rkyv_deserialize_value(archived)?;
+        Ok(Type_A { entries: legacy.into_entries() })
+    }
+}
+
+impl From<&Entries> for LegacyLeaf {
+    /// Drops the optional fields of the key infos.
+    fn from(entries: &Entries) -> Self {
+        let entries = entries
+            .iter()
+            .map(|(key, (info, value))| {
+                (key.clone(), (LegacyKeyInfo { storage_preference: *info.storage_preference() }, value.clone()))
+            })
+            .collect();
+        LegacyLeaf { entries }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::corruption;
+    use crate::test_support::sample_entries;
+
+    const ENTRIES: u32 = 100;
+    const VALUE_LEN: usize = 1000;
+
+    #[test]
+    fn bincode_size_is_exact() {
+        let mut leaf = Type_B { entries: sample_entries(ENTRIES, VALUE_LEN) };
+        assert_eq!(leaf.checked_size(), Ok(codec::bincode_serialize(&leaf).unwrap().len()));
+
+        let sliced = CowBytes::from(vec![1u8; VALUE_LEN]).slice(10, 20);
+        let info = KeyInfo::new(StoragePreference::NONE);
+        leaf.entries.insert(CowBytes::from(&b"sliced"[..]), (info, sliced));
+        assert_eq!(leaf.checked_size(), Ok(codec::bincode_serialize(&leaf).unwrap().len()));
+    }
+
+    #[test]
+    fn rkyv_size_is_upper_bound() {
+        for count in [0, 1, 7, ENTRIES] {
+            let leaf = Type_A { entries: sample_entries(count, VALUE_LEN + count as usize) };
+            let actual = codec::rkyv_serialize(&leaf).unwrap().len();
+            assert!(actual <= leaf.rkyv_size(), "{} > {}", actual, leaf.rkyv_size());
+            assert!(leaf.rkyv_size() - actual < 2 * mem::align_of::<ArchivedType_A>());
+        }
+    }
+
+    #[test]
+    fn bincode_split_respects_bound() {
+        for target_size in [60 * VALUE_LEN, 80 * VALUE_LEN, 200 * VALUE_LEN] {
+            let leaf = Type_B { entries: sample_entries(ENTRIES, VALUE_LEN) };
+            let (left, pivot, right) = leaf.split(target_size).unwrap();
+            for half in [&left, &right] {
+                let size = codec::bincode_serialize(half).unwrap().len();
+                assert!(half.entries.len() == 1 || size <= target_size, "{} > {}", size, target_size);
+            }
+            assert_eq!(left.entries.keys().next_back(), Some(&pivot));
+            assert!(right.entries.keys().all(|key| *key > pivot));
+            assert_eq!(left.entries.len() + right.entries.len(), ENTRIES as usize);
+        }
+
+        // Three nodes are needed, the leaf is returned unchanged.
+        let leaf = Type_B { entries: sample_entries(ENTRIES, VALUE_LEN) };
+        assert_eq!(leaf.split(40 * VALUE_LEN).unwrap_err().entries, sample_entries(ENTRIES, VALUE_LEN));
+    }
+
+    #[test]
+    fn rkyv_split_respects_bound() {
+        for target_size in [60 * VALUE_LEN, 80 * VALUE_LEN, 200 * VALUE_LEN] {
+            let leaf = Type_A { entries: sample_entries(ENTRIES, VALUE_LEN) };
+            let (left, pivot, right) = leaf.split(target_size).unwrap();
+            for half in [&left, &right] {
+                let size = codec::rkyv_serialize(half).unwrap().len();
+                assert!(half.entries.len() == 1 || size <= target_size, "{} > {}", size, target_size);
+            }
+            assert_eq!(left.entries.keys().next_back(), Some(&pivot));
+            assert!(right.entries.keys().all(|key| *key > pivot));
+            assert_eq!(left.entries.len() + right.entries.len(), ENTRIES as usize);
+        }
+
+        // Three nodes are needed, the leaf is returned unchanged.
+        let leaf = Type_A { entries: sample_entries(ENTRIES, VALUE_LEN) };
+        assert_eq!(leaf.split(40 * VALUE_LEN).unwrap_err().entries, sample_entries(ENTRIES, VALUE_LEN));
+    }
+
+    #[test]
+    fn split_single_entry_fails() {
+        let leaf = Type_B { entries: sample_entries(1, VALUE_LEN) };
+        assert!(leaf.split(0).is_err());
+
+        // Halves of a single entry may exceed the target size.
+        let (left, _, right) = Type_B { entries: sample_entries(2, VALUE_LEN) }.split(0).unwrap();
+        assert_eq!((left.entries.len(), right.entries.len()), (1, 1));
+    }
+
+    #[test]
+    fn merge_respects_bound() {
+        let entries = sample_entries(ENTRIES, VALUE_LEN);
+        let max_size = ENTRIES as usize * (VALUE_LEN + mem::size_of::<ArchivedEntry>() + 16);
+
+        let (mut left, _, right) = Type_B { entries: entries.clone() }.split(max_size * 3 / 4).unwrap();
+        let right = left.merge(right, max_size / 2).unwrap_err();
+        left.merge(right, max_size).unwrap();
+        assert_eq!(left.entries, entries);
+        assert!(codec::bincode_serialize(&left).unwrap().len() <= max_size);
+
+        let (mut left, _, right) = Type_A { entries: entries.clone() }.split(max_size * 3 / 4).unwrap();
+        let right = left.merge(right, max_size / 2).unwrap_err();
+        left.merge(right, max_size).unwrap();
+        assert_eq!(left.entries, entries);
+        assert!(codec::rkyv_serialize(&left).unwrap().len() <= max_size);
+    }
+
+    #[test]
+    fn archived_range_matches_btree_range() {
+        let leaf = Type_A { entries: sample_entries(ENTRIES, 8) };
+        let bytes = codec::rkyv_serialize(&leaf).unwrap();
+        let archived = codec::rkyv_check(&bytes).unwrap();
+
+        let bounds = [&b"0"[..], b"1", b"15", b"5", b"99", b"a"]
+            .into_iter()
+            .flat_map(|key| [Bound::Included(key), Bound::Excluded(key)])
+            .chain([Bound::Unbounded]);
+        for start in bounds.clone() {
+            for end in bounds.clone() {
+                let range = (start, end);
+                let expected: Vec<&[u8]> = match (start, end) {
+                    (Bound::Included(a) | Bound::Excluded(a), Bound::Included(b) | Bound::Excluded(b))
+                        if a > b || (a == b && range != (Bound::Included(a), Bound::Included(b))) =>
+                    {
+                        Vec::new()
+                    }
+                    _ => leaf.entries.range::<[u8], _>(range).map(|(key, _)| &key[..]).collect(),
+                };
+                assert_eq!(archived.keys::<[u8], _>(range).collect::<Vec<_>>(), expected, "{:?}", range);
+            }
+        }
+        assert_eq!(archived.range::<[u8], _>(..).len(), ENTRIES as usize);
+    }
+
+    #[test]
+    fn merge_combines_key_infos() {
+        let key = CowBytes::from(&b"key"[..]);
+        let value = SlicedCowBytes::from(CowBytes::from(&b"value"[..]));
+        let mut left = Type_B { entries: BTreeMap::new() };
+        let mut right = Type_B { entries: BTreeMap::new() };
+        let fast = KeyInfo::new(StoragePreference::FAST);
+        let slowest = KeyInfo::new(StoragePreference::SLOWEST);
+        left.entries.insert(key.clone(), (fast.clone(), SlicedCowBytes::default()));
+        right.entries.insert(key.clone(), (slowest, value.clone()));
+        left.merge(right, usize::MAX).unwrap();
+        assert_eq!(left.entries[&key], (fast, value));
+    }
+
+    #[test]
+    fn legacy_leaves_decode() {
+        let entries = sample_entries(ENTRIES, 16);
+        let legacy = LegacyLeaf::from(&entries);
+
+        let bytes = codec::bincode_serialize_value(&legacy).unwrap();
+        let grown = ENTRIES as usize * (KeyInfo::BINCODE_SIZE - LegacyKeyInfo::BINCODE_SIZE);
+        assert_eq!(bytes.len() + grown, Type_B { entries: entries.clone() }.size());
+        assert_eq!(LegacyLeaf::bincode_deserialize(&bytes).unwrap().entries, entries);
+        assert_eq!(codec::bincode_deserialize(&bytes).unwrap_err().phase(), "deserialize");
+
+        let bytes = codec::rkyv_serialize_value(&legacy).unwrap();
+        assert_eq!(LegacyLeaf::rkyv_deserialize(&bytes).unwrap().entries, entries);
+        assert_eq!(codec::rkyv_check(&bytes).err().unwrap().phase(), "validate");
+    }
+
+    #[test]
+    fn legacy_slices_are_checked() {
+        let entries: Entries =
+            sample_entries(2, 16).into_iter().map(|(key, (info, value))| (key, (info, value.subslice(2, 8)))).collect();
+        let bytes = codec::rkyv_serialize_value(&LegacyLeaf::from(&entries)).unwrap();
+        assert_eq!(LegacyLeaf::rkyv_deserialize(&bytes).unwrap().entries, entries);
+
+        // Slices out of bounds pass bytecheck, but not the legacy decoding.
+        let mut out_of_bounds = 0;
+        for mutation in corruption::exhaustive_mutations(bytes.len()) {
+            let mut mutated = rkyv::AlignedVec::new();
+            mutated.extend_from_slice(&mutation.apply(&bytes));
+            if let Ok(archived) = codec::rkyv_check_value::<LegacyLeaf>(&mutated) {
+                if archived.entries.iter().any(|entry| !entry.value.1.is_in_bounds()) {
+                    assert_eq!(LegacyLeaf::rkyv_deserialize(&mutated).unwrap_err().phase(), "validate");
+                    out_of_bounds += 1;
+                }
+            }
+        }
+        assert!(out_of_bounds > 0);
+    }
+}
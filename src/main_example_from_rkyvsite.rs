@@ -2,55 +2,21 @@
 pub mod size;
 pub mod cow_bytes;
 pub mod storage_preference;
+pub mod key_info;
+pub mod archive_format;
+pub mod error;
+pub mod stream;
 
 use crate::cow_bytes::CowBytes;
 use crate::cow_bytes::SlicedCowBytes;
-use crate::storage_preference::StoragePreference;
-use crate::size::StaticSize;
+use crate::error::{Codec, Error, Result};
 
-use std::{collections::BTreeMap, mem};
+use std::collections::BTreeMap;
 
 use rkyv::{Archive, Deserialize, Serialize};
 use rkyv::ser::{Serializer, serializers::AllocSerializer};
 use serde::de::value;
 
-/// Additional information for a single entry. Concerns meta information like
-/// the desired storage level of a key.
-#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize)]
-#[archive(check_bytes)]
-pub struct KeyInfo {
-    storage_preference: StoragePreference,
-}
-
-impl From<&ArchivedKeyInfo> for KeyInfo {
-    fn from(x: &ArchivedKeyInfo) -> Self {
-        KeyInfo {
-            storage_preference: (&x.storage_preference).into()
-        }
-    }
-}
-
-impl StaticSize for KeyInfo {
-    fn static_size() -> usize {
-        mem::size_of::<StoragePreference>()
-    }
-}
-
-impl KeyInfo {
-    pub(crate) fn merge_with_upper(self, upper: KeyInfo) -> KeyInfo {
-        KeyInfo {
-            storage_preference: StoragePreference::choose_faster(
-                self.storage_preference,
-                upper.storage_preference,
-            ),
-        }
-    }
-
-    pub(crate) fn storage_preference(&self) -> &StoragePreference {
-        &self.storage_preference
-    }
-}
-
 #[derive(Archive, Deserialize, Serialize, Debug, PartialEq)]
 #[archive(
     // This will generate a PartialEq impl between our unarchived and archived
@@ -79,9 +45,12 @@ pub struct Type_B {
 const TEXT_FOR_VALUE: &str = "hello world!";
 const ENTRIES_COUNT: u32 = 1000000;
 
+// Buffer sizes of the streaming bincode cases.
+const STREAM_BUFFER_SIZES: [usize; 3] = [8 * 1024, 64 * 1024, 1024 * 1024];
+
 
 
-fn rkyv_case_a_safe() -> u128 {
+fn rkyv_case_a_safe() -> Result<u128> {
     let mut data = Type_A {
         int: 42,
         string: "hello world".to_string(),
@@ -98,18 +67,20 @@ fn rkyv_case_a_safe() -> u128 {
     //let value = CowBytes::from(TEXT_FOR_VALUE.as_bytes());    
     // for number in (1..ENTRIES_COUNT) {
     //     data.entries.insert(CowBytes::from(number.to_string().as_bytes()),
-    //         (KeyInfo { storage_preference: StoragePreference::new(1) },  SlicedCowBytes::from(value.clone())));
+    //         (KeyInfo::new(StoragePreference::new(1)),  SlicedCowBytes::from(value.clone())));
     // }
 
     let start_time = std::time::Instant::now();
 
     let mut serializer = AllocSerializer::<0>::default();
-    serializer.serialize_value(&data).unwrap();
+    serializer.serialize_value(&data).map_err(Error::serialize(Codec::Rkyv))?;
     let bytes = serializer.into_serializer().into_inner();
 
-    let archived = rkyv::check_archived_root::<Type_A>(&bytes[..]).unwrap();
+    let archived = rkyv::check_archived_root::<Type_A>(&bytes[..]).map_err(Error::validate)?;
 
-    let deserialized: Type_A = archived.deserialize(&mut rkyv::Infallible).unwrap();
+    let deserialized: Type_A = archived
+        .deserialize(&mut rkyv::Infallible)
+        .map_err(Error::deserialize(Codec::Rkyv))?;
 
     // for (key, value) in deserialized.entries.iter() {
     //     cloned_data.entries.insert(key.clone(), value.clone());
@@ -122,10 +93,10 @@ fn rkyv_case_a_safe() -> u128 {
     // }
     // assert_eq!(total_entries_fetched, ENTRIES_COUNT);
 
-    start_time.elapsed().as_nanos()
+    Ok(start_time.elapsed().as_nanos())
 }
 
-fn rkyv_case_a_unsafe() -> u128 {
+fn rkyv_case_a_unsafe() -> Result<u128> {
     let mut data = Type_A {
         int: 42,
         string: "hello world".to_string(),
@@ -143,18 +114,20 @@ fn rkyv_case_a_unsafe() -> u128 {
     
     // for number in (1..ENTRIES_COUNT) {
     //     data.entries.insert(CowBytes::from(number.to_string().as_bytes()),
-    //         (KeyInfo { storage_preference: StoragePreference::new(1) },  SlicedCowBytes::from(value.clone())));
+    //         (KeyInfo::new(StoragePreference::new(1)),  SlicedCowBytes::from(value.clone())));
     // }
 
     let start_time = std::time::Instant::now();
 
     let mut serializer = AllocSerializer::<0>::default();
-    serializer.serialize_value(&data).unwrap();
+    serializer.serialize_value(&data).map_err(Error::serialize(Codec::Rkyv))?;
     let bytes = serializer.into_serializer().into_inner();
 
     let archived = unsafe { rkyv::archived_root::<Type_A>(&bytes[..]) };
 
-    let deserialized: Type_A = archived.deserialize(&mut rkyv::Infallible).unwrap();
+    let deserialized: Type_A = archived
+        .deserialize(&mut rkyv::Infallible)
+        .map_err(Error::deserialize(Codec::Rkyv))?;
 
     // for (key, value) in deserialized.entries.iter() {
     //     cloned_data.entries.insert(key.clone(), value.clone());
@@ -167,10 +140,10 @@ fn rkyv_case_a_unsafe() -> u128 {
     // }
     // assert_eq!(total_entries_fetched, ENTRIES_COUNT);
 
-    start_time.elapsed().as_nanos()
+    Ok(start_time.elapsed().as_nanos())
 }
 
-fn rkyv_case_b_safe() -> u128 {
+fn rkyv_case_b_safe() -> Result<u128> {
     let mut data = Type_A {
         int: 42,
         string: "hello world".to_string(),
@@ -188,16 +161,16 @@ fn rkyv_case_b_safe() -> u128 {
     
     // for number in (1..ENTRIES_COUNT) {
     //     data.entries.insert(CowBytes::from(number.to_string().as_bytes()),
-    //         (KeyInfo { storage_preference: StoragePreference::new(1) },  SlicedCowBytes::from(value.clone())));
+    //         (KeyInfo::new(StoragePreference::new(1)),  SlicedCowBytes::from(value.clone())));
     // }
 
     let start_time = std::time::Instant::now();
 
     let mut serializer = AllocSerializer::<0>::default();
-    serializer.serialize_value(&data).unwrap();
+    serializer.serialize_value(&data).map_err(Error::serialize(Codec::Rkyv))?;
     let bytes = serializer.into_serializer().into_inner();
 
-    let archived = rkyv::check_archived_root::<Type_A>(&bytes[..]).unwrap();
+    let archived = rkyv::check_archived_root::<Type_A>(&bytes[..]).map_err(Error::validate)?;
 
 
     cloned_data.int = archived.int;
@@ -214,10 +187,10 @@ fn rkyv_case_b_safe() -> u128 {
     // }
     // assert_eq!(total_entries_fetched, ENTRIES_COUNT);
 
-    start_time.elapsed().as_nanos()
+    Ok(start_time.elapsed().as_nanos())
 }
 
-fn rkyv_case_b_unsafe() -> u128 {
+fn rkyv_case_b_unsafe() -> Result<u128> {
     let mut data = Type_A {
         int: 42,
         string: "hello world".to_string(),
@@ -235,13 +208,13 @@ fn rkyv_case_b_unsafe() -> u128 {
     
     // for number in (1..ENTRIES_COUNT) {
     //     data.entries.insert(CowBytes::from(number.to_string().as_bytes()),
-    //         (KeyInfo { storage_preference: StoragePreference::new(1) },  SlicedCowBytes::from(value.clone())));
+    //         (KeyInfo::new(StoragePreference::new(1)),  SlicedCowBytes::from(value.clone())));
     // }
 
     let start_time = std::time::Instant::now();
 
     let mut serializer = AllocSerializer::<0>::default();
-    serializer.serialize_value(&data).unwrap();
+    serializer.serialize_value(&data).map_err(Error::serialize(Codec::Rkyv))?;
     let bytes = serializer.into_serializer().into_inner();
 
     let archived = unsafe { rkyv::archived_root::<Type_A>(&bytes[..]) };
@@ -268,10 +241,10 @@ fn rkyv_case_b_unsafe() -> u128 {
     // }
     // assert_eq!(total_entries_fetched, ENTRIES_COUNT);
 
-    start_time.elapsed().as_nanos()
+    Ok(start_time.elapsed().as_nanos())
 }
 
-fn serde_case() -> u128 {
+fn serde_case() -> Result<u128> {
     let mut data = Type_B {
         int: 42,
         string: "hello world".to_string(),
@@ -288,7 +261,7 @@ fn serde_case() -> u128 {
     
     // for number in (1..ENTRIES_COUNT) {
     //     data.entries.insert(CowBytes::from(number.to_string().as_bytes()),
-    //         (KeyInfo { storage_preference: StoragePreference::new(1) },  SlicedCowBytes::from(value.clone())));
+    //         (KeyInfo::new(StoragePreference::new(1)),  SlicedCowBytes::from(value.clone())));
     // }
     
     let start_time = std::time::Instant::now();
@@ -297,14 +270,9 @@ fn serde_case() -> u128 {
 
     //let xs: Vec<u8> = bincode::serialize(&data).unwrap();
 
-    bincode::serialize_into(&mut buf, &data)
-    .map_err(|e| {
-        std::io::Error::new(std::io::ErrorKind::InvalidData, e)
-    });
+    bincode::serialize_into(&mut buf, &data).map_err(Error::serialize(Codec::Bincode))?;
 
-    let deserialized = bincode::deserialize::<Type_B>(&buf).map_err(|e| {
-        std::io::Error::new(std::io::ErrorKind::InvalidData, e)
-    }).unwrap();
+    let deserialized = bincode::deserialize::<Type_B>(&buf).map_err(Error::deserialize(Codec::Bincode))?;
 
     // for value in deserialized.entries.iter() {
     //     cloned_data.entries.push(value.clone());
@@ -317,15 +285,154 @@ fn serde_case() -> u128 {
     // }
     // assert_eq!(total_entries_fetched, ENTRIES_COUNT);
     
-    start_time.elapsed().as_nanos()
+    Ok(start_time.elapsed().as_nanos())
+}
+
+fn serde_case_file(buffer_size: usize) -> Result<u128> {
+    let data = Type_B {
+        int: 42,
+        string: "hello world".to_string(),
+        option: Some(vec![1, 2, 3, 4]),
+    };
+    let path = stream::scratch_path("serde_case_file");
+
+    let start_time = std::time::Instant::now();
+
+    stream::bincode_to_file(&data, &path, buffer_size)?;
+
+    let deserialized: Type_B = stream::bincode_from_file(&path, buffer_size)?;
+    std::hint::black_box(deserialized);
+
+    let elapsed = start_time.elapsed().as_nanos();
+    std::fs::remove_file(&path)?;
+    Ok(elapsed)
+}
+
+fn serde_case_pipe(buffer_size: usize) -> Result<u128> {
+    let data = Type_B {
+        int: 42,
+        string: "hello world".to_string(),
+        option: Some(vec![1, 2, 3, 4]),
+    };
+
+    let start_time = std::time::Instant::now();
+
+    let deserialized = stream::bincode_through_pipe(&data, buffer_size)?;
+    std::hint::black_box(deserialized);
+
+    Ok(start_time.elapsed().as_nanos())
+}
+
+fn rkyv_case_a_safe_mmap() -> Result<u128> {
+    let data = Type_A {
+        int: 42,
+        string: "hello world".to_string(),
+        option: Some(vec![1, 2, 3, 4]),
+    };
+    let path = stream::scratch_path("rkyv_case_a_safe_mmap");
+
+    let start_time = std::time::Instant::now();
+
+    let mut serializer = AllocSerializer::<0>::default();
+    serializer.serialize_value(&data).map_err(Error::serialize(Codec::Rkyv))?;
+    let bytes = serializer.into_serializer().into_inner();
+    stream::rkyv_to_file(&bytes, &path)?;
+
+    let map = stream::rkyv_map_file(&path)?;
+    let archived = rkyv::check_archived_root::<Type_A>(&map[..]).map_err(Error::validate)?;
+
+    let deserialized: Type_A = archived
+        .deserialize(&mut rkyv::Infallible)
+        .map_err(Error::deserialize(Codec::Rkyv))?;
+    std::hint::black_box(deserialized);
+
+    let elapsed = start_time.elapsed().as_nanos();
+    drop(map);
+    std::fs::remove_file(&path)?;
+    Ok(elapsed)
+}
+
+fn report(case: &str, result: Result<u128>) {
+    match result {
+        Ok(nanos) => println!("Total time for {}{} ns", case, nanos),
+        Err(e) => println!("Failed {} {} phase: {}", case, e.phase(), e),
+    }
 }
 
 fn main() {
     println!("start!");
 
-    println!("Total time for rkyv_case_a_safe:......{} ns", rkyv_case_a_safe());
-    println!("Total time for rkyv_case_a_unsafe:....{} ns", rkyv_case_a_unsafe());
-    println!("Total time for serde_case:............{} ns", serde_case());
-    println!("Total time for rkyv_case_b_safe:......{} ns", rkyv_case_b_safe());
-    println!("Total time for rkyv_case_b_unsafe:....{} ns", rkyv_case_b_unsafe());
+    report("rkyv_case_a_safe:......", rkyv_case_a_safe());
+    report("rkyv_case_a_unsafe:....", rkyv_case_a_unsafe());
+    report("serde_case:............", serde_case());
+    report("rkyv_case_b_safe:......", rkyv_case_b_safe());
+    report("rkyv_case_b_unsafe:....", rkyv_case_b_unsafe());
+
+    report("rkyv_case_a_safe (write, mmap):......", rkyv_case_a_safe_mmap());
+    for buffer_size in STREAM_BUFFER_SIZES {
+        let kib = buffer_size / 1024;
+        report(&format!("serde_case (file, {} KiB buffer):......", kib), serde_case_file(buffer_size));
+        report(&format!("serde_case (pipe, {} KiB buffer):......", kib), serde_case_pipe(buffer_size));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::archive_format;
+    use std::{fs, path::PathBuf};
+
+    // The golden vectors of this scenario, see the docs of `golden.rs` in
+    // `main_mp_of_cowbytes`.
+    const SCENARIO: &str = "example_from_rkyvsite";
+
+    fn golden_a() -> Type_A {
+        Type_A { int: 42, string: "hello world".to_string(), option: Some(vec![1, 2, 3, 4]) }
+    }
+
+    fn golden_b() -> Type_B {
+        Type_B { int: 42, string: "hello world".to_string(), option: Some(vec![1, 2, 3, 4]) }
+    }
+
+    fn vector_path(codec: Codec) -> PathBuf {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR")).join(format!("fixtures/vectors/{}.{}", SCENARIO, codec))
+    }
+
+    fn rkyv_encode(data: &Type_A) -> rkyv::AlignedVec {
+        rkyv::to_bytes::<_, 256>(data).unwrap()
+    }
+
+    #[test]
+    fn bincode_encoding_matches_vector() {
+        let bytes = fs::read(vector_path(Codec::Bincode)).unwrap();
+        assert_eq!(bincode::serialize(&golden_b()).unwrap(), bytes, "encoding changed, see the docs of golden.rs");
+        let decoded: Type_B = bincode::deserialize(&bytes).unwrap();
+        assert_eq!(bincode::serialize(&decoded).unwrap(), bytes);
+    }
+
+    #[test]
+    #[cfg_attr(
+        any(feature = "archive_be", feature = "size_64", all(target_endian = "big", not(feature = "archive_le"))),
+        ignore = "the rkyv vectors are le/32-bit archives"
+    )]
+    fn rkyv_encoding_matches_vector() {
+        assert!(archive_format::reads_fixtures());
+        let bytes = fs::read(vector_path(Codec::Rkyv)).unwrap();
+        assert_eq!(rkyv_encode(&golden_a())[..], bytes[..], "encoding changed, see the docs of golden.rs");
+        // Files are read into unaligned buffers.
+        let mut aligned = rkyv::AlignedVec::with_capacity(bytes.len());
+        aligned.extend_from_slice(&bytes);
+        let archived = rkyv::check_archived_root::<Type_A>(&aligned).unwrap();
+        let decoded: Type_A = archived.deserialize(&mut rkyv::Infallible).unwrap();
+        assert_eq!(decoded, golden_a());
+    }
+
+    /// Writes the vectors of this scenario anew.
+    #[test]
+    #[ignore]
+    fn regenerate_vectors() {
+        assert!(archive_format::reads_fixtures(), "vectors are written in {}", archive_format::ArchiveFormat::FIXTURES);
+        fs::write(vector_path(Codec::Rkyv), rkyv_encode(&golden_a())).unwrap();
+        fs::write(vector_path(Codec::Bincode), bincode::serialize(&golden_b()).unwrap()).unwrap();
+    }
 }
@@ -2,55 +2,45 @@
 pub mod size;
 pub mod cow_bytes;
 pub mod storage_preference;
+pub mod corruption;
+pub mod codec;
+pub mod error;
+pub mod allocation;
+pub mod stream;
+pub mod internal;
+pub mod message;
+pub mod leaf;
+pub mod indexed_leaf;
+pub mod front_coding;
+pub mod in_place;
+pub mod tiers;
+pub mod migration;
+pub mod key_info;
+pub mod versioned;
+#[cfg(test)]
+pub mod golden;
+pub mod archive_format;
+pub mod parallel;
+pub mod shared;
+#[cfg(test)]
+pub mod test_support;
 
 use crate::cow_bytes::CowBytes;
+use crate::key_info::KeyInfo;
 use crate::cow_bytes::SlicedCowBytes;
+use crate::message::Message;
 use crate::storage_preference::StoragePreference;
-use crate::size::StaticSize;
+use crate::error::{Error, Result};
+use crate::allocation::{measure, Phase, PhaseStats};
+use crate::codec::Codec;
+use crate::archive_format::ArchiveFormat;
+use crate::tiers::TierRegistry;
 
-use std::{collections::BTreeMap, mem};
+use std::{collections::BTreeMap, ops::Bound, sync::Arc};
 
 use rkyv::{Archive, Deserialize, Serialize};
-use rkyv::ser::{Serializer, serializers::AllocSerializer};
 use serde::de::value;
 
-/// Additional information for a single entry. Concerns meta information like
-/// the desired storage level of a key.
-#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize)]
-#[archive(check_bytes)]
-pub struct KeyInfo {
-    storage_preference: StoragePreference,
-}
-
-impl From<&ArchivedKeyInfo> for KeyInfo {
-    fn from(x: &ArchivedKeyInfo) -> Self {
-        KeyInfo {
-            storage_preference: (&x.storage_preference).into()
-        }
-    }
-}
-
-impl StaticSize for KeyInfo {
-    fn static_size() -> usize {
-        mem::size_of::<StoragePreference>()
-    }
-}
-
-impl KeyInfo {
-    pub(crate) fn merge_with_upper(self, upper: KeyInfo) -> KeyInfo {
-        KeyInfo {
-            storage_preference: StoragePreference::choose_faster(
-                self.storage_preference,
-                upper.storage_preference,
-            ),
-        }
-    }
-
-    pub(crate) fn storage_preference(&self) -> &StoragePreference {
-        &self.storage_preference
-    }
-}
-
 #[derive(Archive, Deserialize, Serialize, Debug, PartialEq)]
 #[archive(
     // This will generate a PartialEq impl between our unarchived and archived
@@ -67,7 +57,7 @@ pub struct Type_A {
     pub entries: BTreeMap<CowBytes, (KeyInfo, SlicedCowBytes)>,
 }
 
-#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
 pub struct Type_B {
     //#[with(rkyv::with::AsVec)]
     pub entries: BTreeMap<CowBytes, (KeyInfo, SlicedCowBytes)>,
@@ -76,14 +66,71 @@ pub struct Type_B {
 //const TEXT_FOR_VALUE: &str = "Lorem Ipsum is simply dummy text of the printing and typesetting industry. Lorem Ipsum has been the industry's standard dummy text ever since the 1500s, when an unknown printer took a galley of type and scrambled it to make a type specimen book. It has survived not only five centuries, but also the leap into electronic typesetting, remaining essentially unchanged. It was popularised in the 1960s with the release of Letraset sheets containing Lorem Ipsum passages, and more recently with desktop publishing software like Aldus PageMaker including versions of Lorem Ipsum. It is a long established fact that a reader will be distracted by the readable content of a page when looking at its layout. The point of using Lorem Ipsum is that it has a more-or-less normal distribution of letters, as opposed to using 'Content here, content here', making it look like readable English. Many desktop publishing packages and web page editors now use Lorem Ipsum as their default model text, and a search for 'lorem ipsum' will uncover many web sites still in their infancy. Various versions have evolved over the years, sometimes by accident, sometimes on purpose (injected humour and the like).";
 //const TEXT_FOR_VALUE: &str = "hello world!";
 
+// Values of 128 KiB each. Beyond about 16000 entries, the rkyv cases need the
+// size_64 feature, see archive_format.
 const ENTRIES_COUNT: u32 = 10000;
 
+// Node shape and count for the fresh vs. reused serializer comparison.
+const REUSE_NODE_ENTRIES: u32 = 64;
+const REUSE_VALUE_LEN: usize = 4 * 1024;
+const REUSE_NODE_WRITES: usize = 1000;
+
+// Buffer sizes of the streaming bincode cases.
+const STREAM_BUFFER_SIZES: [usize; 3] = [8 * 1024, 64 * 1024, 1024 * 1024];
+
+// Shape of the internal node cases.
+const INTERNAL_CHILDREN: u32 = 128;
+const INTERNAL_MESSAGES_PER_CHILD: u32 = 64;
+const INTERNAL_VALUE_LEN: usize = 1024;
+
+// Shape of the key scan cases, which read the keys within SCAN_RANGE.
+const SCAN_ENTRIES: u32 = 10000;
+const SCAN_VALUE_LEN: usize = 4 * 1024;
+const SCAN_RANGE: (&str, &str) = ("2000", "3000");
+
+// Shape of the front coding comparison; small values put the keys in focus.
+const FRONT_CODING_ENTRIES: u32 = 10000;
+const FRONT_CODING_VALUE_LEN: usize = 16;
+
+// Node shape and number of updates of the in-place update cases.
+const IN_PLACE_ENTRIES: u32 = 1000;
+const IN_PLACE_VALUE_LEN: usize = 4 * 1024;
+const IN_PLACE_UPDATES: u32 = 100;
+
+// Tier descriptions for the per-tier round trips; the defaults are used if the file is missing.
+const TIERS_CONFIG: &str = "tiers.toml";
+const TIER_ENTRIES: u32 = 1000;
+const TIER_VALUE_LEN: usize = 4 * 1024;
+
+// A leaf of more than 4 GiB, only written with the size_64 feature. Its values
+// are as large as messages may make them.
+const LARGE_NODE_ENTRIES: u32 = 65;
+const LARGE_NODE_VALUE_LEN: usize = message::MAX_VALUE_SIZE;
+
+// Batch of independent leaves round-tripped per thread count.
+const PARALLEL_NODES: u32 = 256;
+const PARALLEL_NODE_ENTRIES: u32 = 64;
+const PARALLEL_VALUE_LEN: usize = 4 * 1024;
+const PARALLEL_THREADS: [usize; 4] = [1, 2, 4, 8];
+
+// One leaf shared by reader threads doing random point lookups.
+const SHARED_ENTRIES: u32 = 10000;
+const SHARED_VALUE_LEN: usize = 1024;
+const SHARED_LOOKUPS: usize = 100_000;
+const SHARED_THREADS: [usize; 4] = [1, 2, 4, 8];
+
+// Archived integers summed per byte order.
+const ENDIANNESS_VALUES: u64 = 1 << 22;
+
+#[global_allocator]
+static GLOBAL: allocation::CountingAllocator = allocation::CountingAllocator;
+
 fn fill_data_a(data: &mut Type_A) {   
     let TEXT_FOR_VALUE : Vec<u8> =  vec![1u8; 128*1024];
     let value = CowBytes::from(TEXT_FOR_VALUE);
     for number in (1..ENTRIES_COUNT) {
         data.entries.insert(CowBytes::from(number.to_string().as_bytes()),
-            (KeyInfo { storage_preference: StoragePreference::new(1) },  SlicedCowBytes::from(value.clone())));
+            (KeyInfo::new(StoragePreference::new(1)),  SlicedCowBytes::from(value.clone())));
     }
 }
 
@@ -92,12 +139,24 @@ fn fill_data_b(data: &mut Type_B) {
     let value = CowBytes::from(TEXT_FOR_VALUE);
     for number in (1..ENTRIES_COUNT) {
         data.entries.insert(CowBytes::from(number.to_string().as_bytes()),
-            (KeyInfo { storage_preference: StoragePreference::new(1) },  SlicedCowBytes::from(value.clone())));
+            (KeyInfo::new(StoragePreference::new(1)),  SlicedCowBytes::from(value.clone())));
     }
 }
 
+/// Builds `count` entries keyed by their decimal number, whose values are
+/// `value_len` bytes long and share one buffer.
+fn bench_entries(count: u32, value_len: usize) -> BTreeMap<CowBytes, (KeyInfo, SlicedCowBytes)> {
+    let value = CowBytes::from(vec![1u8; value_len]);
+    (1..=count)
+        .map(|number| {
+            let info = KeyInfo::new(StoragePreference::new(1));
+            (CowBytes::from(number.to_string().as_bytes()), (info, SlicedCowBytes::from(value.clone())))
+        })
+        .collect()
+}
+
 
-fn rkyv_case_a_safe() -> u128 {
+fn rkyv_case_a_safe() -> Result<u128> {
     let mut data = Type_A {
         entries: BTreeMap::new()
     };
@@ -110,18 +169,17 @@ fn rkyv_case_a_safe() -> u128 {
     //let value = CowBytes::from(TEXT_FOR_VALUE.as_bytes());    
     // for number in (1..ENTRIES_COUNT) {
     //     data.entries.insert(CowBytes::from(number.to_string().as_bytes()),
-    //         (KeyInfo { storage_preference: StoragePreference::new(1) },  SlicedCowBytes::from(value.clone())));
+    //         (KeyInfo::new(StoragePreference::new(1)),  SlicedCowBytes::from(value.clone())));
     // }
 
     let start_time = std::time::Instant::now();
 
-    let mut serializer = AllocSerializer::<0>::default();
-    serializer.serialize_value(&data).unwrap();
-    let bytes = serializer.into_serializer().into_inner();
+    let bytes = codec::rkyv_serialize(&data)?;
 
-    let archived = rkyv::check_archived_root::<Type_A>(&bytes[..]).unwrap();
+    let archived = codec::rkyv_check(&bytes[..])?;
 
-    let deserialized: Type_A = archived.deserialize(&mut rkyv::de::deserializers::SharedDeserializeMap::new()).unwrap();
+    let deserialized: Type_A = codec::rkyv_deserialize(archived)?;
+    std::hint::black_box(&deserialized);
 
     // for (key, value) in deserialized.entries.iter() {
     //     cloned_data.entries.insert(key.clone(), value.clone());
@@ -134,10 +192,10 @@ fn rkyv_case_a_safe() -> u128 {
     // }
     // assert_eq!(total_entries_fetched, ENTRIES_COUNT);
 
-    start_time.elapsed().as_millis()
+    Ok(start_time.elapsed().as_millis())
 }
 
-fn rkyv_case_a_unsafe() -> u128 {
+fn rkyv_case_a_unsafe() -> Result<u128> {
     let mut data = Type_A {
         entries: BTreeMap::new()
     };
@@ -151,18 +209,17 @@ fn rkyv_case_a_unsafe() -> u128 {
     
     // for number in (1..ENTRIES_COUNT) {
     //     data.entries.insert(CowBytes::from(number.to_string().as_bytes()),
-    //         (KeyInfo { storage_preference: StoragePreference::new(1) },  SlicedCowBytes::from(value.clone())));
+    //         (KeyInfo::new(StoragePreference::new(1)),  SlicedCowBytes::from(value.clone())));
     // }
 
     let start_time = std::time::Instant::now();
 
-    let mut serializer = AllocSerializer::<0>::default();
-    serializer.serialize_value(&data).unwrap();
-    let bytes = serializer.into_serializer().into_inner();
+    let bytes = codec::rkyv_serialize(&data)?;
 
-    let archived = unsafe { rkyv::archived_root::<Type_A>(&bytes[..]) };
+    let archived = unsafe { codec::rkyv_access(&bytes[..])? };
 
-    let deserialized: Type_A = archived.deserialize(&mut rkyv::de::deserializers::SharedDeserializeMap::new()).unwrap();
+    let deserialized: Type_A = codec::rkyv_deserialize(archived)?;
+    std::hint::black_box(&deserialized);
 
     // for (key, value) in deserialized.entries.iter() {
     //     cloned_data.entries.insert(key.clone(), value.clone());
@@ -175,10 +232,10 @@ fn rkyv_case_a_unsafe() -> u128 {
     // }
     // assert_eq!(total_entries_fetched, ENTRIES_COUNT);
 
-    start_time.elapsed().as_millis()
+    Ok(start_time.elapsed().as_millis())
 }
 
-fn rkyv_case_b_safe() -> u128 {
+fn rkyv_case_b_safe() -> Result<u128> {
     let mut data = Type_A {
         entries: BTreeMap::new()
     };
@@ -192,16 +249,14 @@ fn rkyv_case_b_safe() -> u128 {
     
     // for number in (1..ENTRIES_COUNT) {
     //     data.entries.insert(CowBytes::from(number.to_string().as_bytes()),
-    //         (KeyInfo { storage_preference: StoragePreference::new(1) },  SlicedCowBytes::from(value.clone())));
+    //         (KeyInfo::new(StoragePreference::new(1)),  SlicedCowBytes::from(value.clone())));
     // }
 
     let start_time = std::time::Instant::now();
 
-    let mut serializer = AllocSerializer::<0>::default();
-    serializer.serialize_value(&data).unwrap();
-    let bytes = serializer.into_serializer().into_inner();
+    let bytes = codec::rkyv_serialize(&data)?;
 
-    let archived = rkyv::check_archived_root::<Type_A>(&bytes[..]).unwrap();
+    let archived = codec::rkyv_check(&bytes[..])?;
 
     for entry in archived.entries.iter() {
         cloned_data.entries.insert(CowBytes::from(&entry.key) /*CowBytes::from(entry.key.inner.to_vec())*/,
@@ -220,10 +275,10 @@ fn rkyv_case_b_safe() -> u128 {
     // }
     // assert_eq!(total_entries_fetched, ENTRIES_COUNT);
 
-    start_time.elapsed().as_millis()
+    Ok(start_time.elapsed().as_millis())
 }
 
-fn rkyv_case_b_unsafe() -> u128 {
+fn rkyv_case_b_unsafe() -> Result<u128> {
     let mut data = Type_A {
         entries: BTreeMap::new()
     };
@@ -237,16 +292,14 @@ fn rkyv_case_b_unsafe() -> u128 {
     
     // for number in (1..ENTRIES_COUNT) {
     //     data.entries.insert(CowBytes::from(number.to_string().as_bytes()),
-    //         (KeyInfo { storage_preference: StoragePreference::new(1) },  SlicedCowBytes::from(value.clone())));
+    //         (KeyInfo::new(StoragePreference::new(1)),  SlicedCowBytes::from(value.clone())));
     // }
 
     let start_time = std::time::Instant::now();
 
-    let mut serializer = AllocSerializer::<0>::default();
-    serializer.serialize_value(&data).unwrap();
-    let bytes = serializer.into_serializer().into_inner();
+    let bytes = codec::rkyv_serialize(&data)?;
 
-    let archived = unsafe { rkyv::archived_root::<Type_A>(&bytes[..]) };
+    let archived = unsafe { codec::rkyv_access(&bytes[..])? };
 
     for entry in archived.entries.iter() {
         cloned_data.entries.insert(CowBytes::from(&entry.key) /*CowBytes::from(entry.key.inner.to_vec())*/,
@@ -265,10 +318,10 @@ fn rkyv_case_b_unsafe() -> u128 {
     // }
     // assert_eq!(total_entries_fetched, ENTRIES_COUNT);
 
-    start_time.elapsed().as_millis()
+    Ok(start_time.elapsed().as_millis())
 }
 
-fn rkyv_case_b_safe_access_one_entity() -> u128 {
+fn rkyv_case_b_safe_access_one_entity() -> Result<u128> {
     let mut data = Type_A {
         entries: BTreeMap::new()
     };
@@ -282,16 +335,14 @@ fn rkyv_case_b_safe_access_one_entity() -> u128 {
     
     // for number in (1..ENTRIES_COUNT) {
     //     data.entries.insert(CowBytes::from(number.to_string().as_bytes()),
-    //         (KeyInfo { storage_preference: StoragePreference::new(1) },  SlicedCowBytes::from(value.clone())));
+    //         (KeyInfo::new(StoragePreference::new(1)),  SlicedCowBytes::from(value.clone())));
     // }
 
     let start_time = std::time::Instant::now();
 
-    let mut serializer = AllocSerializer::<0>::default();
-    serializer.serialize_value(&data).unwrap();
-    let bytes = serializer.into_serializer().into_inner();
+    let bytes = codec::rkyv_serialize(&data)?;
 
-    let archived = rkyv::check_archived_root::<Type_A>(&bytes[..]).unwrap();
+    let archived = codec::rkyv_check(&bytes[..])?;
 
     for entry in archived.entries.iter() {
         cloned_data.entries.insert(CowBytes::from(&entry.key) /*CowBytes::from(entry.key.inner.to_vec())*/,
@@ -311,10 +362,10 @@ fn rkyv_case_b_safe_access_one_entity() -> u128 {
     // }
     // assert_eq!(total_entries_fetched, ENTRIES_COUNT);
 
-    start_time.elapsed().as_millis()
+    Ok(start_time.elapsed().as_millis())
 }
 
-fn rkyv_case_b_unsafe_access_one_entity() -> u128 {
+fn rkyv_case_b_unsafe_access_one_entity() -> Result<u128> {
     let mut data = Type_A {
         entries: BTreeMap::new()
     };
@@ -328,16 +379,14 @@ fn rkyv_case_b_unsafe_access_one_entity() -> u128 {
     
     // for number in (1..ENTRIES_COUNT) {
     //     data.entries.insert(CowBytes::from(number.to_string().as_bytes()),
-    //         (KeyInfo { storage_preference: StoragePreference::new(1) },  SlicedCowBytes::from(value.clone())));
+    //         (KeyInfo::new(StoragePreference::new(1)),  SlicedCowBytes::from(value.clone())));
     // }
 
     let start_time = std::time::Instant::now();
 
-    let mut serializer = AllocSerializer::<0>::default();
-    serializer.serialize_value(&data).unwrap();
-    let bytes = serializer.into_serializer().into_inner();
+    let bytes = codec::rkyv_serialize(&data)?;
 
-    let archived = unsafe { rkyv::archived_root::<Type_A>(&bytes[..]) };
+    let archived = unsafe { codec::rkyv_access(&bytes[..])? };
 
     for entry in archived.entries.iter() {
         cloned_data.entries.insert(CowBytes::from(&entry.key) /*CowBytes::from(entry.key.inner.to_vec())*/,
@@ -357,10 +406,10 @@ fn rkyv_case_b_unsafe_access_one_entity() -> u128 {
     // }
     // assert_eq!(total_entries_fetched, ENTRIES_COUNT);
 
-    start_time.elapsed().as_millis()
+    Ok(start_time.elapsed().as_millis())
 }
 
-fn serde_case() -> u128 {
+fn serde_case() -> Result<u128> {
     let mut data = Type_B {
         entries: BTreeMap::new()
     };
@@ -374,23 +423,17 @@ fn serde_case() -> u128 {
     
     // for number in (1..ENTRIES_COUNT) {
     //     data.entries.insert(CowBytes::from(number.to_string().as_bytes()),
-    //         (KeyInfo { storage_preference: StoragePreference::new(1) },  SlicedCowBytes::from(value.clone())));
+    //         (KeyInfo::new(StoragePreference::new(1)),  SlicedCowBytes::from(value.clone())));
     // }
     
     let start_time = std::time::Instant::now();
 
-    let mut buf: Vec<u8> = Vec::new();
-
     //let xs: Vec<u8> = bincode::serialize(&data).unwrap();
 
-    bincode::serialize_into(&mut buf, &data)
-    .map_err(|e| {
-        std::io::Error::new(std::io::ErrorKind::InvalidData, e)
-    });
+    let buf = codec::bincode_serialize(&data)?;
 
-    let deserialized = bincode::deserialize::<Type_B>(&buf).map_err(|e| {
-        std::io::Error::new(std::io::ErrorKind::InvalidData, e)
-    }).unwrap();
+    let deserialized = codec::bincode_deserialize(&buf)?;
+    std::hint::black_box(&deserialized);
 
     // for (key, value) in deserialized.entries.iter() {
     //     cloned_data.entries.insert(key.clone(), value.clone());
@@ -404,17 +447,780 @@ fn serde_case() -> u128 {
     // }
     // assert_eq!(total_entries_fetched, ENTRIES_COUNT);
 
-    start_time.elapsed().as_millis()
+    Ok(start_time.elapsed().as_millis())
+}
+
+fn serde_case_file(buffer_size: usize) -> Result<u128> {
+    let mut data = Type_B {
+        entries: BTreeMap::new()
+    };
+
+    fill_data_b(&mut data);
+    let path = stream::scratch_path("serde_case_file");
+
+    let start_time = std::time::Instant::now();
+
+    stream::bincode_to_file(&data, &path, buffer_size)?;
+
+    let deserialized: Type_B = stream::bincode_from_file(&path, buffer_size)?;
+    std::hint::black_box(&deserialized);
+
+    let elapsed = start_time.elapsed().as_millis();
+    std::fs::remove_file(&path)?;
+    Ok(elapsed)
+}
+
+fn serde_case_pipe(buffer_size: usize) -> Result<u128> {
+    let mut data = Type_B {
+        entries: BTreeMap::new()
+    };
+
+    fill_data_b(&mut data);
+
+    let start_time = std::time::Instant::now();
+
+    let deserialized = stream::bincode_through_pipe(&data, buffer_size)?;
+    std::hint::black_box(&deserialized);
+
+    Ok(start_time.elapsed().as_millis())
+}
+
+fn rkyv_case_a_safe_mmap() -> Result<u128> {
+    let mut data = Type_A {
+        entries: BTreeMap::new()
+    };
+
+    fill_data_a(&mut data);
+    let path = stream::scratch_path("rkyv_case_a_safe_mmap");
+
+    let start_time = std::time::Instant::now();
+
+    let bytes = codec::rkyv_serialize(&data)?;
+    stream::rkyv_to_file(&bytes, &path)?;
+
+    let map = stream::rkyv_map_file(&path)?;
+    let archived = codec::rkyv_check(&map[..])?;
+
+    let deserialized: Type_A = codec::rkyv_deserialize(archived)?;
+    std::hint::black_box(&deserialized);
+
+    let elapsed = start_time.elapsed().as_millis();
+    drop(map);
+    std::fs::remove_file(&path)?;
+    Ok(elapsed)
+}
+
+/// Streams a leaf larger than 4 GiB into a file, maps it back and looks up
+/// every entry. The leaf is dropped before mapping, so the input and the
+/// archive are never in memory at the same time.
+fn rkyv_case_large_node_mmap() -> Result<u128> {
+    let data = Type_A { entries: bench_entries(LARGE_NODE_ENTRIES, LARGE_NODE_VALUE_LEN) };
+    let keys: Vec<CowBytes> = data.entries.keys().cloned().collect();
+    let path = stream::scratch_path("rkyv_case_large_node_mmap");
+
+    let start_time = std::time::Instant::now();
+
+    let len = stream::rkyv_serialize_to_file(&data, data.rkyv_size(), &path, 1024 * 1024)?;
+    if len <= 4 << 30 {
+        return Err(Error::Config { message: format!("large node of {} bytes does not exceed 4 GiB", len) });
+    }
+    drop(data);
+
+    let map = stream::rkyv_map_file(&path)?;
+    let archived = codec::rkyv_check(&map[..])?;
+    for key in &keys {
+        let idx = archived.entries.binary_search_by(|entry| entry.key.as_ref().cmp(&key[..]));
+        let value = idx.ok().and_then(|idx| archived.entries[idx].value.1.bytes());
+        if value.and_then(|value| value.last()) != Some(&1) {
+            return Err(Error::Validate { context: format!("value of key {:?} is missing or corrupted", &key[..]) });
+        }
+    }
+
+    let elapsed = start_time.elapsed().as_millis();
+    drop(map);
+    std::fs::remove_file(&path)?;
+    Ok(elapsed)
+}
+
+fn rkyv_case_b_safe_mmap() -> Result<u128> {
+    let mut data = Type_A {
+        entries: BTreeMap::new()
+    };
+
+    fill_data_a(&mut data);
+    let path = stream::scratch_path("rkyv_case_b_safe_mmap");
+
+    let start_time = std::time::Instant::now();
+
+    let bytes = codec::rkyv_serialize(&data)?;
+    stream::rkyv_to_file(&bytes, &path)?;
+
+    let map = stream::rkyv_map_file(&path)?;
+    let archived = codec::rkyv_check(&map[..])?;
+
+    let cloned_data = codec::rkyv_convert(archived)?;
+    std::hint::black_box(&cloned_data);
+
+    let elapsed = start_time.elapsed().as_millis();
+    drop(map);
+    std::fs::remove_file(&path)?;
+    Ok(elapsed)
+}
+
+fn rkyv_case_internal_safe() -> Result<u128> {
+    let insert = Message::Insert(SlicedCowBytes::from(CowBytes::from(vec![1u8; INTERNAL_VALUE_LEN])));
+    let data = internal::sample_internal_node(INTERNAL_CHILDREN, INTERNAL_MESSAGES_PER_CHILD, |_| insert.clone());
+
+    let start_time = std::time::Instant::now();
+
+    let bytes = codec::rkyv_serialize_value(&data)?;
+
+    let archived = internal::rkyv_check(&bytes[..])?;
+
+    let deserialized: internal::InternalNode = codec::rkyv_deserialize_value(archived)?;
+    std::hint::black_box(&deserialized);
+
+    Ok(start_time.elapsed().as_millis())
+}
+
+fn rkyv_case_internal_safe_access_one_child() -> Result<u128> {
+    let insert = Message::Insert(SlicedCowBytes::from(CowBytes::from(vec![1u8; INTERNAL_VALUE_LEN])));
+    let data = internal::sample_internal_node(INTERNAL_CHILDREN, INTERNAL_MESSAGES_PER_CHILD, |_| insert.clone());
+    let key = CowBytes::from((INTERNAL_CHILDREN * INTERNAL_MESSAGES_PER_CHILD / 2).to_string().as_bytes());
+
+    let start_time = std::time::Instant::now();
+
+    let bytes = codec::rkyv_serialize_value(&data)?;
+
+    let archived = internal::rkyv_check(&bytes[..])?;
+
+    let child = &archived.children[archived.child_index(&key)];
+    let mut messages = BTreeMap::new();
+    for entry in child.messages.iter() {
+        messages.insert(CowBytes::from(&entry.key),
+        ( KeyInfo::from(&entry.value.0),  codec::rkyv_deserialize_value::<Message>(&entry.value.1)?));
+    }
+
+    Ok(start_time.elapsed().as_millis())
+}
+
+fn serde_case_internal() -> Result<u128> {
+    let insert = Message::Insert(SlicedCowBytes::from(CowBytes::from(vec![1u8; INTERNAL_VALUE_LEN])));
+    let data = internal::sample_internal_node(INTERNAL_CHILDREN, INTERNAL_MESSAGES_PER_CHILD, |_| insert.clone());
+
+    let start_time = std::time::Instant::now();
+
+    let buf = codec::bincode_serialize_value(&data)?;
+
+    let deserialized: internal::InternalNode = codec::bincode_deserialize_value(&buf)?;
+    std::hint::black_box(&deserialized);
+
+    Ok(start_time.elapsed().as_millis())
+}
+
+fn rkyv_case_messages_safe() -> Result<u128> {
+    let data = internal::sample_internal_node(INTERNAL_CHILDREN, INTERNAL_MESSAGES_PER_CHILD, |number| message::sample_message(number, INTERNAL_VALUE_LEN));
+
+    let start_time = std::time::Instant::now();
+
+    let bytes = codec::rkyv_serialize_value(&data)?;
+
+    let archived = internal::rkyv_check(&bytes[..])?;
+
+    let deserialized: internal::InternalNode = codec::rkyv_deserialize_value(archived)?;
+    std::hint::black_box(&deserialized);
+
+    Ok(start_time.elapsed().as_millis())
+}
+
+fn serde_case_messages() -> Result<u128> {
+    let data = internal::sample_internal_node(INTERNAL_CHILDREN, INTERNAL_MESSAGES_PER_CHILD, |number| message::sample_message(number, INTERNAL_VALUE_LEN));
+
+    let start_time = std::time::Instant::now();
+
+    let buf = codec::bincode_serialize_value(&data)?;
+
+    let deserialized: internal::InternalNode = codec::bincode_deserialize_value(&buf)?;
+    std::hint::black_box(&deserialized);
+
+    Ok(start_time.elapsed().as_millis())
+}
+
+fn rkyv_case_key_scan(validate: bool) -> Result<u128> {
+    let data = Type_A { entries: bench_entries(SCAN_ENTRIES, SCAN_VALUE_LEN) };
+    let bytes = codec::rkyv_serialize(&data)?;
+
+    let start_time = std::time::Instant::now();
+
+    let archived = if validate { codec::rkyv_check(&bytes[..])? } else { unsafe { codec::rkyv_access(&bytes[..])? } };
+
+    let keys: Vec<CowBytes> = archived.keys(SCAN_RANGE.0.as_bytes()..SCAN_RANGE.1.as_bytes()).map(CowBytes::from).collect();
+    std::hint::black_box(&keys);
+
+    Ok(start_time.elapsed().as_millis())
+}
+
+fn serde_case_key_scan() -> Result<u128> {
+    let data = Type_B { entries: bench_entries(SCAN_ENTRIES, SCAN_VALUE_LEN) };
+    let buf = codec::bincode_serialize(&data)?;
+
+    let start_time = std::time::Instant::now();
+
+    let deserialized = codec::bincode_deserialize(&buf)?;
+
+    let range = (Bound::Included(SCAN_RANGE.0.as_bytes()), Bound::Excluded(SCAN_RANGE.1.as_bytes()));
+    let keys: Vec<CowBytes> = deserialized.entries.range::<[u8], _>(range).map(|(key, _)| key.clone()).collect();
+    std::hint::black_box(&keys);
+
+    Ok(start_time.elapsed().as_millis())
+}
+
+fn rkyv_case_full_key_scan(validate: bool) -> Result<u128> {
+    let data = Type_A { entries: bench_entries(SCAN_ENTRIES, SCAN_VALUE_LEN) };
+    let bytes = codec::rkyv_serialize(&data)?;
+
+    let start_time = std::time::Instant::now();
+
+    let archived = if validate { codec::rkyv_check(&bytes[..])? } else { unsafe { codec::rkyv_access(&bytes[..])? } };
+
+    let keys: Vec<CowBytes> = archived.keys::<[u8], _>(..).map(CowBytes::from).collect();
+    std::hint::black_box(&keys);
+
+    Ok(start_time.elapsed().as_millis())
+}
+
+fn rkyv_case_indexed_key_scan(validate: bool) -> Result<u128> {
+    let data = indexed_leaf::IndexedLeaf::from_entries(&bench_entries(SCAN_ENTRIES, SCAN_VALUE_LEN))?;
+    let bytes = codec::rkyv_serialize_value(&data)?;
+
+    let start_time = std::time::Instant::now();
+
+    let archived = if validate {
+        indexed_leaf::rkyv_check(&bytes[..])?
+    } else {
+        unsafe { rkyv::archived_root::<indexed_leaf::IndexedLeaf>(&bytes[..]) }
+    };
+
+    let keys: Vec<CowBytes> = archived.keys().map(CowBytes::from).collect();
+    std::hint::black_box(&keys);
+
+    Ok(start_time.elapsed().as_millis())
+}
+
+fn serde_case_full_key_scan() -> Result<u128> {
+    let data = Type_B { entries: bench_entries(SCAN_ENTRIES, SCAN_VALUE_LEN) };
+    let buf = codec::bincode_serialize(&data)?;
+
+    let start_time = std::time::Instant::now();
+
+    let deserialized = codec::bincode_deserialize(&buf)?;
+
+    let keys: Vec<CowBytes> = deserialized.entries.keys().cloned().collect();
+    std::hint::black_box(&keys);
+
+    Ok(start_time.elapsed().as_millis())
+}
+
+fn serde_case_indexed_key_scan() -> Result<u128> {
+    let data = indexed_leaf::IndexedLeaf::from_entries(&bench_entries(SCAN_ENTRIES, SCAN_VALUE_LEN))?;
+    let mut buf = Vec::new();
+    data.bincode_write(&mut buf);
+
+    let start_time = std::time::Instant::now();
+
+    let parsed = indexed_leaf::BincodeIndexedLeaf::parse(&buf)?;
+
+    let keys: Vec<CowBytes> = parsed.keys().map(CowBytes::from).collect();
+    std::hint::black_box(&keys);
+
+    Ok(start_time.elapsed().as_millis())
+}
+
+fn rkyv_phases() -> Result<Vec<PhaseStats>> {
+    let mut stats = Vec::new();
+
+    let (data, fill) = measure(Phase::Fill, || {
+        let mut data = Type_A { entries: BTreeMap::new() };
+        fill_data_a(&mut data);
+        data
+    });
+    stats.push(fill);
+
+    let (bytes, serialize) = measure(Phase::Serialize, || codec::rkyv_serialize(&data));
+    stats.push(serialize);
+    let bytes = bytes?;
+
+    let (archived, validate) = measure(Phase::Validate, || codec::rkyv_check(&bytes[..]));
+    stats.push(validate);
+    let archived = archived?;
+
+    let (deserialized, deserialize) = measure(Phase::Deserialize, || codec::rkyv_deserialize(archived));
+    stats.push(deserialize);
+    deserialized?;
+
+    let (converted, convert) = measure(Phase::Convert, || codec::rkyv_convert(archived));
+    stats.push(convert);
+    converted?;
+
+    Ok(stats)
+}
+
+fn serde_phases() -> Result<Vec<PhaseStats>> {
+    let mut stats = Vec::new();
+
+    let (data, fill) = measure(Phase::Fill, || {
+        let mut data = Type_B { entries: BTreeMap::new() };
+        fill_data_b(&mut data);
+        data
+    });
+    stats.push(fill);
+
+    let (buf, serialize) = measure(Phase::Serialize, || codec::bincode_serialize(&data));
+    stats.push(serialize);
+    let buf = buf?;
+
+    let (deserialized, deserialize) = measure(Phase::Deserialize, || codec::bincode_deserialize(&buf));
+    stats.push(deserialize);
+    deserialized?;
+
+    Ok(stats)
+}
+
+fn serializer_reuse() -> Result<Vec<(&'static str, PhaseStats)>> {
+    let entries = bench_entries(REUSE_NODE_ENTRIES, REUSE_VALUE_LEN);
+    let data_a = Type_A { entries: entries.clone() };
+    let data_b = Type_B { entries };
+    let mut stats = Vec::new();
+
+    let (result, fresh) = measure(Phase::Serialize, || -> Result<()> {
+        for _ in 0..REUSE_NODE_WRITES {
+            codec::rkyv_serialize(&data_a)?;
+        }
+        Ok(())
+    });
+    result?;
+    stats.push(("rkyv fresh AllocSerializer<0>", fresh));
+
+    let mut writer = codec::RkyvWriter::<0>::new();
+    let (result, reused) = measure(Phase::Serialize, || -> Result<()> {
+        for _ in 0..REUSE_NODE_WRITES {
+            writer.write(&data_a)?;
+        }
+        Ok(())
+    });
+    result?;
+    stats.push(("rkyv reused RkyvWriter<0>", reused));
+
+    let mut writer = codec::RkyvWriter::<4096>::new();
+    let (result, reused) = measure(Phase::Serialize, || -> Result<()> {
+        for _ in 0..REUSE_NODE_WRITES {
+            writer.write(&data_a)?;
+        }
+        Ok(())
+    });
+    result?;
+    stats.push(("rkyv reused RkyvWriter<4096>", reused));
+
+    let (result, fresh) = measure(Phase::Serialize, || -> Result<()> {
+        for _ in 0..REUSE_NODE_WRITES {
+            codec::bincode_serialize(&data_b)?;
+        }
+        Ok(())
+    });
+    result?;
+    stats.push(("bincode fresh Vec", fresh));
+
+    let mut writer = codec::BincodeWriter::default();
+    let (result, reused) = measure(Phase::Serialize, || -> Result<()> {
+        for _ in 0..REUSE_NODE_WRITES {
+            writer.write(&data_b)?;
+        }
+        Ok(())
+    });
+    result?;
+    stats.push(("bincode reused BincodeWriter", reused));
+
+    Ok(stats)
+}
+
+fn front_coding_comparison() -> Result<Vec<(&'static str, usize, u128)>> {
+    let entries = bench_entries(FRONT_CODING_ENTRIES, FRONT_CODING_VALUE_LEN);
+    let front_coded = front_coding::FrontCodedLeaf::from_entries(&entries, front_coding::DEFAULT_RESTART_INTERVAL);
+    let mut stats = Vec::new();
+
+    let bytes = codec::rkyv_serialize(&Type_A { entries: entries.clone() })?;
+    let archived = codec::rkyv_check(&bytes[..])?;
+    let start_time = std::time::Instant::now();
+    for key in entries.keys() {
+        archived.entries.binary_search_by(|entry| entry.key.as_ref().cmp(&key[..])).expect("key is present");
+    }
+    stats.push(("rkyv Type_A", bytes.len(), start_time.elapsed().as_micros()));
+
+    let bytes = codec::rkyv_serialize_value(&front_coded)?;
+    let archived = front_coding::rkyv_check(&bytes[..])?;
+    let start_time = std::time::Instant::now();
+    for key in entries.keys() {
+        archived.get(key).expect("key is present");
+    }
+    stats.push(("rkyv front coded", bytes.len(), start_time.elapsed().as_micros()));
+
+    let buf = codec::bincode_serialize(&Type_B { entries: entries.clone() })?;
+    let deserialized = codec::bincode_deserialize(&buf)?;
+    let start_time = std::time::Instant::now();
+    for key in entries.keys() {
+        deserialized.entries.get(key).expect("key is present");
+    }
+    stats.push(("bincode Type_B", buf.len(), start_time.elapsed().as_micros()));
+
+    let buf = codec::bincode_serialize_value(&front_coded)?;
+    let deserialized = front_coding::bincode_deserialize(&buf)?;
+    let start_time = std::time::Instant::now();
+    for key in entries.keys() {
+        deserialized.get(key).expect("key is present");
+    }
+    stats.push(("bincode front coded", buf.len(), start_time.elapsed().as_micros()));
+
+    Ok(stats)
+}
+
+fn load_tiers() -> Result<TierRegistry> {
+    let path = std::path::Path::new(TIERS_CONFIG);
+    if path.exists() {
+        TierRegistry::load(path)
+    } else {
+        Ok(TierRegistry::default())
+    }
+}
+
+/// Round trips one leaf through the codec of each tier and reports the size
+/// padded to the tier's block size.
+fn tier_round_trips(registry: &TierRegistry) -> Result<Vec<(String, Codec, usize, u128)>> {
+    let entries = bench_entries(TIER_ENTRIES, TIER_VALUE_LEN);
+    let mut stats = Vec::new();
+    for (_, tier) in registry.iter() {
+        let start_time = std::time::Instant::now();
+        let len = match tier.codec {
+            Codec::Rkyv => {
+                let bytes = codec::rkyv_serialize(&Type_A { entries: entries.clone() })?;
+                codec::rkyv_deserialize(codec::rkyv_check(&bytes[..])?)?;
+                bytes.len()
+            }
+            Codec::Bincode => {
+                let buf = codec::bincode_serialize(&Type_B { entries: entries.clone() })?;
+                codec::bincode_deserialize(&buf)?;
+                buf.len()
+            }
+        };
+        let elapsed = start_time.elapsed().as_micros();
+        stats.push((tier.name.clone(), tier.codec, len.next_multiple_of(tier.block_size), elapsed));
+    }
+    Ok(stats)
+}
+
+/// Round trips a batch of leaves with each codec and thread count, together
+/// with the speedup over a single thread.
+fn parallel_round_trips() -> Result<Vec<(Codec, usize, parallel::Throughput, f64)>> {
+    let nodes_a: Vec<Type_A> = (0..PARALLEL_NODES)
+        .map(|_| Type_A { entries: bench_entries(PARALLEL_NODE_ENTRIES, PARALLEL_VALUE_LEN) })
+        .collect();
+    let nodes_b: Vec<Type_B> = nodes_a.iter().map(|node| Type_B { entries: node.entries.clone() }).collect();
+    let mut stats = Vec::new();
+
+    for codec in [Codec::Rkyv, Codec::Bincode] {
+        let mut single = None;
+        for threads in PARALLEL_THREADS {
+            let throughput = match codec {
+                Codec::Rkyv => parallel::rkyv_round_trips(&nodes_a, threads)?,
+                Codec::Bincode => parallel::bincode_round_trips(&nodes_b, threads)?,
+            };
+            // PARALLEL_THREADS starts with a single thread.
+            let single = *single.get_or_insert(throughput.elapsed);
+            stats.push((codec, threads, throughput, single.as_secs_f64() / throughput.elapsed.as_secs_f64()));
+        }
+    }
+
+    Ok(stats)
+}
+
+/// Point lookups per second of reader threads sharing one leaf, as an
+/// archive in memory or in a mapped file, or deserialized behind a lock.
+fn shared_lookups() -> Result<Vec<(&'static str, usize, f64)>> {
+    let entries = bench_entries(SHARED_ENTRIES, SHARED_VALUE_LEN);
+    let keys: Arc<[CowBytes]> = entries.keys().cloned().collect();
+    let bytes = codec::rkyv_serialize(&Type_A { entries: entries.clone() })?;
+    let path = stream::scratch_path("shared_lookups");
+    stream::rkyv_to_file(&bytes, &path)?;
+
+    let in_memory = Arc::new(shared::SharedArchive::from_bytes(bytes)?);
+    let mapped = Arc::new(shared::SharedArchive::map_file(&path)?);
+    let locked = Arc::new(parking_lot::RwLock::new(Type_B { entries }));
+    let archive_get = |archive: &shared::SharedArchive, key: &[u8]| archive.get(key).map(<[u8]>::len);
+    let mut stats = Vec::new();
+
+    for threads in SHARED_THREADS {
+        let lookups = shared::concurrent_lookups(in_memory.clone(), keys.clone(), threads, SHARED_LOOKUPS, archive_get);
+        stats.push(("rkyv archive", threads, lookups.per_sec()));
+        let lookups = shared::concurrent_lookups(mapped.clone(), keys.clone(), threads, SHARED_LOOKUPS, archive_get);
+        stats.push(("rkyv archive (mmap)", threads, lookups.per_sec()));
+        let lookups =
+            shared::concurrent_lookups(locked.clone(), keys.clone(), threads, SHARED_LOOKUPS, shared::locked_get);
+        stats.push(("bincode Type_B (RwLock)", threads, lookups.per_sec()));
+    }
+
+    drop(mapped);
+    std::fs::remove_file(&path)?;
+    Ok(stats)
+}
+
+/// Reads an archived `u64`, which is a plain `u64` unless an `archive_*`
+/// feature selects an explicit byte order.
+fn native_u64(value: &rkyv::Archived<u64>) -> u64 {
+    rkyv::from_archived!(*value)
+}
+
+/// Sums archived integers in the byte order of this build and in explicit
+/// little and big endian, which the `archive_*` features do not affect.
+fn endianness_comparison() -> Result<Vec<(String, u128)>> {
+    let values: Vec<u64> = (0..ENDIANNESS_VALUES).collect();
+    let mut stats = Vec::new();
+
+    let bytes = codec::rkyv_serialize_value(&values)?;
+    let archived = codec::rkyv_check_value::<Vec<u64>>(&bytes[..])?;
+    let start_time = std::time::Instant::now();
+    let sum: u64 = archived.iter().map(native_u64).sum();
+    std::hint::black_box(sum);
+    stats.push((format!("u64, {}", ArchiveFormat::CURRENT), start_time.elapsed().as_micros()));
+
+    let little: Vec<_> = values.iter().map(|value| rkyv::rend::u64_le::new(*value)).collect();
+    let bytes = codec::rkyv_serialize_value(&little)?;
+    let archived = codec::rkyv_check_value::<Vec<rkyv::rend::u64_le>>(&bytes[..])?;
+    let start_time = std::time::Instant::now();
+    let sum: u64 = archived.iter().map(|value| value.value()).sum();
+    std::hint::black_box(sum);
+    stats.push(("u64_le".to_string(), start_time.elapsed().as_micros()));
+
+    let big: Vec<_> = values.iter().map(|value| rkyv::rend::u64_be::new(*value)).collect();
+    let bytes = codec::rkyv_serialize_value(&big)?;
+    let archived = codec::rkyv_check_value::<Vec<rkyv::rend::u64_be>>(&bytes[..])?;
+    let start_time = std::time::Instant::now();
+    let sum: u64 = archived.iter().map(|value| value.value()).sum();
+    std::hint::black_box(sum);
+    stats.push(("u64_be".to_string(), start_time.elapsed().as_micros()));
+
+    Ok(stats)
+}
+
+fn in_place_key(update: u32) -> CowBytes {
+    CowBytes::from((update * 7 % IN_PLACE_ENTRIES + 1).to_string().as_bytes())
+}
+
+fn rkyv_case_in_place_update() -> Result<u128> {
+    let data = Type_A { entries: bench_entries(IN_PLACE_ENTRIES, IN_PLACE_VALUE_LEN) };
+    let mut bytes = codec::rkyv_serialize(&data)?;
+    let value = vec![2u8; IN_PLACE_VALUE_LEN];
+
+    let start_time = std::time::Instant::now();
+
+    let mut archived = in_place::rkyv_check_mut(std::pin::Pin::new(&mut bytes[..]))?;
+    for update in 0..IN_PLACE_UPDATES {
+        let key = in_place_key(update);
+        archived.as_mut().set_storage_preference(&key, StoragePreference::FASTEST)?;
+        archived.as_mut().overwrite_value(&key, &value)?;
+    }
+
+    Ok(start_time.elapsed().as_millis())
+}
+
+fn rkyv_case_in_place_update_mmap() -> Result<u128> {
+    let data = Type_A { entries: bench_entries(IN_PLACE_ENTRIES, IN_PLACE_VALUE_LEN) };
+    let path = stream::scratch_path("rkyv_case_in_place_update_mmap");
+    stream::rkyv_to_file(&codec::rkyv_serialize(&data)?, &path)?;
+    let value = vec![2u8; IN_PLACE_VALUE_LEN];
+
+    let start_time = std::time::Instant::now();
+
+    let mut map = stream::rkyv_map_file_mut(&path)?;
+    let mut archived = in_place::rkyv_check_mut(std::pin::Pin::new(&mut map[..]))?;
+    for update in 0..IN_PLACE_UPDATES {
+        let key = in_place_key(update);
+        archived.as_mut().set_storage_preference(&key, StoragePreference::FASTEST)?;
+        archived.as_mut().overwrite_value(&key, &value)?;
+    }
+    map.flush()?;
+
+    let elapsed = start_time.elapsed().as_millis();
+    drop(map);
+    std::fs::remove_file(&path)?;
+    Ok(elapsed)
+}
+
+fn rkyv_case_reserialize_update() -> Result<u128> {
+    let data = Type_A { entries: bench_entries(IN_PLACE_ENTRIES, IN_PLACE_VALUE_LEN) };
+    let mut bytes = codec::rkyv_serialize(&data)?;
+    let value = SlicedCowBytes::from(CowBytes::from(vec![2u8; IN_PLACE_VALUE_LEN]));
+
+    let start_time = std::time::Instant::now();
+
+    for update in 0..IN_PLACE_UPDATES {
+        let mut deserialized = codec::rkyv_deserialize(codec::rkyv_check(&bytes[..])?)?;
+        if let Some(entry) = deserialized.entries.get_mut(&in_place_key(update)) {
+            *entry = (KeyInfo::new(StoragePreference::FASTEST), value.clone());
+        }
+        bytes = codec::rkyv_serialize(&deserialized)?;
+    }
+
+    Ok(start_time.elapsed().as_millis())
+}
+
+fn serde_case_reserialize_update() -> Result<u128> {
+    let data = Type_B { entries: bench_entries(IN_PLACE_ENTRIES, IN_PLACE_VALUE_LEN) };
+    let mut buf = codec::bincode_serialize(&data)?;
+    let value = SlicedCowBytes::from(CowBytes::from(vec![2u8; IN_PLACE_VALUE_LEN]));
+
+    let start_time = std::time::Instant::now();
+
+    for update in 0..IN_PLACE_UPDATES {
+        let mut deserialized = codec::bincode_deserialize(&buf)?;
+        if let Some(entry) = deserialized.entries.get_mut(&in_place_key(update)) {
+            *entry = (KeyInfo::new(StoragePreference::FASTEST), value.clone());
+        }
+        buf = codec::bincode_serialize(&deserialized)?;
+    }
+
+    Ok(start_time.elapsed().as_millis())
+}
+
+fn report_phases(case: &str, result: Result<Vec<PhaseStats>>) {
+    match result {
+        Ok(stats) => {
+            println!("Phases for {}", case);
+            for phase in stats {
+                println!("    {}", phase);
+            }
+        }
+        Err(e) => println!("Failed {} {} phase: {}", case, e.phase(), e),
+    }
+}
+
+fn report(case: &str, result: Result<u128>) {
+    match result {
+        Ok(millis) => println!("Total time for {}{} ms", case, millis),
+        Err(e) => println!("Failed {} {} phase: {}", case, e.phase(), e),
+    }
 }
 
 fn main() {
     println!("start!");
+    println!("rkyv archive format: {}", ArchiveFormat::CURRENT);
+
+    report("rkyv_case_a_safe:......", rkyv_case_a_safe());
+    report("rkyv_case_a_unsafe:....", rkyv_case_a_unsafe());
+    report("serde_case:............", serde_case());
+    report("rkyv_case_b_safe:......", rkyv_case_b_safe());
+    report("rkyv_case_b_unsafe:....", rkyv_case_b_unsafe());
+    report("rkyv_case_b_safe (access one entry):......", rkyv_case_b_safe_access_one_entity());
+    report("rkyv_case_b_unsafe (access one entry):....", rkyv_case_b_unsafe_access_one_entity());
+
+    report("rkyv_case_a_safe (write, mmap):......", rkyv_case_a_safe_mmap());
+    report("rkyv_case_b_safe (write, mmap):......", rkyv_case_b_safe_mmap());
+    if ArchiveFormat::CURRENT.max_archive_len() > LARGE_NODE_ENTRIES as u64 * LARGE_NODE_VALUE_LEN as u64 {
+        report("rkyv_case_large_node (> 4 GiB, stream, mmap):......", rkyv_case_large_node_mmap());
+    } else {
+        println!("Skipped rkyv_case_large_node: {} archives are limited to {} bytes", ArchiveFormat::CURRENT,
+            ArchiveFormat::CURRENT.max_archive_len());
+    }
+    for buffer_size in STREAM_BUFFER_SIZES {
+        let kib = buffer_size / 1024;
+        report(&format!("serde_case (file, {} KiB buffer):......", kib), serde_case_file(buffer_size));
+        report(&format!("serde_case (pipe, {} KiB buffer):......", kib), serde_case_pipe(buffer_size));
+    }
+
+    report("rkyv_case_internal_safe:......", rkyv_case_internal_safe());
+    report("rkyv_case_internal_safe (access one child):......", rkyv_case_internal_safe_access_one_child());
+    report("serde_case_internal:..........", serde_case_internal());
+    report("rkyv_case_messages_safe (insert/delete/upsert):......", rkyv_case_messages_safe());
+    report("serde_case_messages (insert/delete/upsert):..........", serde_case_messages());
+
+    report("rkyv_case_key_scan_safe:......", rkyv_case_key_scan(true));
+    report("rkyv_case_key_scan_unsafe:....", rkyv_case_key_scan(false));
+    report("serde_case_key_scan:..........", serde_case_key_scan());
+    report("rkyv_case_full_key_scan_safe (Type_A):......", rkyv_case_full_key_scan(true));
+    report("rkyv_case_full_key_scan_unsafe (Type_A):....", rkyv_case_full_key_scan(false));
+    report("rkyv_case_full_key_scan_safe (indexed):.....", rkyv_case_indexed_key_scan(true));
+    report("rkyv_case_full_key_scan_unsafe (indexed):...", rkyv_case_indexed_key_scan(false));
+    report("serde_case_full_key_scan (Type_B):..........", serde_case_full_key_scan());
+    report("serde_case_full_key_scan (indexed):.........", serde_case_indexed_key_scan());
+
+    report("rkyv_case_in_place_update:..............", rkyv_case_in_place_update());
+    report("rkyv_case_in_place_update (mmap):.......", rkyv_case_in_place_update_mmap());
+    report("rkyv_case_reserialize_update:...........", rkyv_case_reserialize_update());
+    report("serde_case_reserialize_update:..........", serde_case_reserialize_update());
+
+    report_phases("rkyv_case_a_safe:", rkyv_phases());
+    report_phases("serde_case:", serde_phases());
+
+    match serializer_reuse() {
+        Ok(stats) => {
+            println!("Serializing {} nodes, fresh vs. reused", REUSE_NODE_WRITES);
+            for (case, phase) in stats {
+                println!("    {:<30} {}", case, phase);
+            }
+        }
+        Err(e) => println!("Failed serializer_reuse: {} phase: {}", e.phase(), e),
+    }
+
+    match front_coding_comparison() {
+        Ok(stats) => {
+            println!("Encoded size and {} lookups, plain vs. front coded keys", FRONT_CODING_ENTRIES);
+            for (case, size, micros) in stats {
+                println!("    {:<20} {:>10} bytes {:>8} us", case, size, micros);
+            }
+        }
+        Err(e) => println!("Failed front_coding_comparison: {} phase: {}", e.phase(), e),
+    }
+
+    match load_tiers().and_then(|registry| tier_round_trips(&registry)) {
+        Ok(stats) => {
+            println!("Round trip of a {} entry leaf per tier, padded to its block size", TIER_ENTRIES);
+            for (name, codec, size, micros) in stats {
+                println!("    {:<10} {:<8} {:>10} bytes {:>8} us", name, codec, size, micros);
+            }
+        }
+        Err(e) => println!("Failed tier_round_trips: {} phase: {}", e.phase(), e),
+    }
+
+    match parallel_round_trips() {
+        Ok(stats) => {
+            println!("Round trips of {} leaves of {} entries per thread count", PARALLEL_NODES, PARALLEL_NODE_ENTRIES);
+            for (codec, threads, throughput, speedup) in stats {
+                println!("    {:<8} {:>2} threads {} {:>5.2}x", codec, threads, throughput, speedup);
+            }
+        }
+        Err(e) => println!("Failed parallel_round_trips: {} phase: {}", e.phase(), e),
+    }
+
+    match shared_lookups() {
+        Ok(stats) => {
+            println!("{} lookups per thread in one shared leaf of {} entries", SHARED_LOOKUPS, SHARED_ENTRIES);
+            for (case, threads, per_sec) in stats {
+                println!("    {:<25} {:>2} threads {:>12.0} lookups/s", case, threads, per_sec);
+            }
+        }
+        Err(e) => println!("Failed shared_lookups: {} phase: {}", e.phase(), e),
+    }
+
+    match endianness_comparison() {
+        Ok(stats) => {
+            println!("Summing {} archived integers per byte order", ENDIANNESS_VALUES);
+            for (case, micros) in stats {
+                println!("    {:<30} {:>8} us", case, micros);
+            }
+        }
+        Err(e) => println!("Failed endianness_comparison: {} phase: {}", e.phase(), e),
+    }
 
-    println!("Total time for rkyv_case_a_safe:......{} ms", rkyv_case_a_safe());
-    println!("Total time for rkyv_case_a_unsafe:....{} ms", rkyv_case_a_unsafe());
-    println!("Total time for serde_case:............{} ms", serde_case());
-    println!("Total time for rkyv_case_b_safe:......{} ms", rkyv_case_b_safe());
-    println!("Total time for rkyv_case_b_unsafe:....{} ms", rkyv_case_b_unsafe());
-    println!("Total time for rkyv_case_b_safe (access one entry):......{} ms", rkyv_case_b_safe_access_one_entity());
-    println!("Total time for rkyv_case_b_unsafe (access one entry):....{} ms", rkyv_case_b_unsafe_access_one_entity());
+    let corrupted = Type_A { entries: bench_entries(16, 64) };
+    let (rkyv_report, bincode_report) = corruption::run(&corrupted, corruption::exhaustive_mutations);
+    println!("Corrupted buffers for rkyv_case_a_safe:...{:?}", rkyv_report);
+    println!("Corrupted buffers for serde_case:.........{:?}", bincode_report);
 }
@@ -0,0 +1,224 @@
+//! This module provides `Message`, the operation buffered for a key in an
+//! internal node until it is flushed down to the leaf.
+//!
+//! Messages for the same key are merged on their way down, so a buffer holds
+//! at most one message per key. Applying a message to the value stored in the
+//! leaf yields the new value, or `None` if the key is deleted.
+
+use crate::cow_bytes::{CowBytes, SlicedCowBytes};
+use crate::error::{Error, Result};
+use crate::size::Size;
+
+/// Largest value an upsert may produce, which is also the value length of
+/// the large node case, the largest values the benchmark stores. A decoded
+/// upsert is not trusted to stay below it, so an offset near `u32::MAX`
+/// fails instead of zero-filling gigabytes.
+pub const MAX_VALUE_SIZE: usize = 64 * 1024 * 1024;
+
+/// Overwrites `data.len()` bytes of a value starting at `offset`.
+#[derive(
+    Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize, rkyv::Archive, rkyv::Serialize,
+    rkyv::Deserialize,
+)]
+#[archive(check_bytes)]
+pub struct Upsert {
+    pub offset: u32,
+    pub data: SlicedCowBytes,
+}
+
+/// A buffered operation on a single key.
+#[derive(
+    Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize, rkyv::Archive, rkyv::Serialize,
+    rkyv::Deserialize,
+)]
+#[archive(check_bytes)]
+pub enum Message {
+    /// Replaces the value.
+    Insert(SlicedCowBytes),
+    /// Removes the key.
+    Delete,
+    /// Patches the value in order. A missing value is treated as empty, and
+    /// gaps in front of a patch are filled with zeros.
+    Upsert(Vec<Upsert>),
+}
+
+impl Size for Upsert {
+    fn size(&self) -> usize {
+        4 + self.data.size()
+    }
+}
+
+impl Size for Message {
+    fn size(&self) -> usize {
+        // bincode encodes the variant as u32.
+        4 + match self {
+            Message::Insert(value) => value.size(),
+            Message::Delete => 0,
+            Message::Upsert(upserts) => 8 + upserts.iter().map(Size::size).sum::<usize>(),
+        }
+    }
+}
+
+impl Message {
+    /// Combines `self` with the newer message `upper` into one message which
+    /// has the same effect as applying both in order. Fails if an upsert
+    /// applied on the way exceeds [MAX_VALUE_SIZE].
+    pub fn merge_with_upper(self, upper: Message) -> Result<Message> {
+        Ok(match (self, upper) {
+            (_, upper @ (Message::Insert(_) | Message::Delete)) => upper,
+            (Message::Upsert(mut upserts), Message::Upsert(upper)) => {
+                upserts.extend(upper);
+                Message::Upsert(upserts)
+            }
+            (lower, upper) => {
+                let base = lower.apply(None)?;
+                Message::Insert(upper.apply(base)?.unwrap_or_default())
+            }
+        })
+    }
+
+    /// Applies the message to the value `base` and returns the new value.
+    /// Fails if an upsert would grow the value beyond [MAX_VALUE_SIZE].
+    pub fn apply(self, base: Option<SlicedCowBytes>) -> Result<Option<SlicedCowBytes>> {
+        match self {
+            Message::Insert(value) => Ok(Some(value)),
+            Message::Delete => Ok(None),
+            Message::Upsert(upserts) => {
+                let mut value = CowBytes::from(base.as_deref().unwrap_or_default());
+                for upsert in upserts {
+                    let offset = upsert.offset as usize;
+                    let end = match offset.checked_add(upsert.data.len()) {
+                        Some(end) if end <= MAX_VALUE_SIZE => end,
+                        _ => {
+                            let (len, limit) = (upsert.data.len(), MAX_VALUE_SIZE);
+                            return Err(Error::Upsert { offset: upsert.offset, len, limit });
+                        }
+                    };
+                    value.fill_zeros_up_to(end);
+                    value[offset..end].copy_from_slice(&upsert.data);
+                }
+                Ok(Some(SlicedCowBytes::from(value)))
+            }
+        }
+    }
+
+    /// Applies `messages`, ordered from oldest to newest, to `base`.
+    pub fn apply_all(
+        base: Option<SlicedCowBytes>,
+        messages: impl IntoIterator<Item = Message>,
+    ) -> Result<Option<SlicedCowBytes>> {
+        messages.into_iter().try_fold(base, |value, message| message.apply(value))
+    }
+}
+
+impl ArchivedMessage {
+    /// Returns whether all slices of the message stay within their data.
+    pub fn is_in_bounds(&self) -> bool {
+        match self {
+            ArchivedMessage::Insert(value) => value.is_in_bounds(),
+            ArchivedMessage::Delete => true,
+            ArchivedMessage::Upsert(upserts) => upserts.iter().all(|upsert| upsert.data.is_in_bounds()),
+        }
+    }
+}
+
+/// Returns the `number`th message of a mix of inserts, deletes and upserts.
+/// Inserts carry `value_len` bytes, upserts patch four 16 byte ranges.
+pub fn sample_message(number: u32, value_len: usize) -> Message {
+    match number % 3 {
+        0 => Message::Insert(SlicedCowBytes::from(CowBytes::from(vec![1u8; value_len]))),
+        1 => Message::Delete,
+        _ => Message::Upsert(
+            (0..4)
+                .map(|idx| Upsert {
+                    offset: idx * 64,
+                    data: SlicedCowBytes::from(CowBytes::from(vec![2u8; 16])),
+                })
+                .collect(),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec;
+
+    fn bytes(value: &[u8]) -> SlicedCowBytes {
+        SlicedCowBytes::from(CowBytes::from(value))
+    }
+
+    fn upsert(offset: u32, data: &[u8]) -> Message {
+        Message::Upsert(vec![Upsert { offset, data: bytes(data) }])
+    }
+
+    #[test]
+    fn apply_chain() {
+        let chain = vec![upsert(1, b"xy"), upsert(4, b"z"), Message::Delete, upsert(2, b"ab")];
+        assert_eq!(Message::apply_all(Some(bytes(b"hello")), chain[..2].to_vec()).unwrap(), Some(bytes(b"hxylz")));
+        assert_eq!(Message::apply_all(None, chain[..1].to_vec()).unwrap(), Some(bytes(b"\0xy")));
+        assert_eq!(Message::apply_all(Some(bytes(b"hello")), chain[..3].to_vec()).unwrap(), None);
+        assert_eq!(Message::apply_all(Some(bytes(b"hello")), chain.clone()).unwrap(), Some(bytes(b"\0\0ab")));
+    }
+
+    #[test]
+    fn merge_matches_apply() {
+        let messages = [
+            Message::Insert(bytes(b"hello")),
+            Message::Delete,
+            upsert(1, b"xy"),
+            upsert(6, b"!"),
+        ];
+        for base in [None, Some(bytes(b"base"))] {
+            for lower in messages.iter() {
+                for upper in messages.iter() {
+                    let merged = lower.clone().merge_with_upper(upper.clone()).unwrap();
+                    assert_eq!(
+                        merged.apply(base.clone()).unwrap(),
+                        Message::apply_all(base.clone(), [lower.clone(), upper.clone()]).unwrap(),
+                        "{:?} then {:?}",
+                        lower,
+                        upper
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn oversized_upserts_fail() {
+        let limit = MAX_VALUE_SIZE as u32;
+        assert_eq!(upsert(limit - 2, b"ab").apply(None).unwrap().unwrap().len(), MAX_VALUE_SIZE);
+        for offset in [limit - 1, u32::MAX] {
+            assert_eq!(upsert(offset, b"ab").apply(Some(bytes(b"base"))).unwrap_err().phase(), "apply");
+            assert!(Message::Delete.merge_with_upper(upsert(offset, b"ab")).is_err());
+        }
+
+        // A decoded upsert is bounded just the same.
+        let bytes = codec::bincode_serialize_value(&upsert(u32::MAX, b"ab")).unwrap();
+        let decoded: Message = codec::bincode_deserialize_value(&bytes).unwrap();
+        assert!(decoded.apply(None).is_err());
+    }
+
+    #[test]
+    fn size_matches_bincode() {
+        for number in 0..3 {
+            let message = sample_message(number, 100);
+            assert_eq!(message.size(), codec::bincode_serialize_value(&message).unwrap().len());
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        for number in 0..3 {
+            let message = sample_message(number, 100);
+            let bytes = codec::rkyv_serialize_value(&message).unwrap();
+            let archived = codec::rkyv_check_value::<Message>(&bytes).unwrap();
+            assert!(archived.is_in_bounds());
+            assert_eq!(codec::rkyv_deserialize_value::<Message>(archived).unwrap(), message);
+
+            let bytes = codec::bincode_serialize_value(&message).unwrap();
+            assert_eq!(codec::bincode_deserialize_value::<Message>(&bytes).unwrap(), message);
+        }
+    }
+}
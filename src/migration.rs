@@ -0,0 +1,292 @@
+//! This module provides the automated migration policy which computes the
+//! [AtomicSystemStoragePreference] of keys or nodes from their accesses.
+//!
+//! A [MigrationEngine] counts the accesses of every tracked object. At the end
+//! of each epoch, [MigrationEngine::apply] asks a [MigrationPolicy] whether to
+//! promote, demote or keep each object and moves its system preference one
+//! class up or down. Objects start out without a system preference, which
+//! counts as the slowest class.
+
+use crate::storage_preference::{
+    ArchivedAtomicSystemStoragePreference, AtomicSystemStoragePreference, StorageClasses, StoragePreference,
+};
+
+use std::{collections::HashMap, hash::Hash, pin::Pin};
+
+use rkyv::vec::ArchivedVec;
+
+/// Access statistics of one object, in ticks of the engine's logical clock.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AccessStats {
+    /// Accesses, halved at the end of every epoch so that old accesses fade.
+    pub count: u64,
+    /// Tick of the latest access, `None` if never accessed.
+    pub last_access: Option<u64>,
+}
+
+/// The outcome of a [MigrationPolicy] for one object.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decision {
+    Promote,
+    Keep,
+    Demote,
+}
+
+/// Decides where objects should be stored, based on their accesses.
+pub trait MigrationPolicy {
+    fn decide(&self, stats: &AccessStats, now: u64) -> Decision;
+}
+
+/// Least frequently used: promotes objects accessed at least `hot` times and
+/// demotes objects accessed less than `cold` times.
+#[derive(Debug, Clone, Copy)]
+pub struct Lfu {
+    pub hot: u64,
+    pub cold: u64,
+}
+
+impl MigrationPolicy for Lfu {
+    fn decide(&self, stats: &AccessStats, _now: u64) -> Decision {
+        if stats.count >= self.hot {
+            Decision::Promote
+        } else if stats.count < self.cold {
+            Decision::Demote
+        } else {
+            Decision::Keep
+        }
+    }
+}
+
+/// Least recently used: promotes objects accessed within the last `hot`
+/// ticks and demotes objects not accessed within the last `cold` ticks.
+#[derive(Debug, Clone, Copy)]
+pub struct Lru {
+    pub hot: u64,
+    pub cold: u64,
+}
+
+impl MigrationPolicy for Lru {
+    fn decide(&self, stats: &AccessStats, now: u64) -> Decision {
+        match stats.last_access.map(|tick| now - tick) {
+            Some(age) if age < self.hot => Decision::Promote,
+            Some(age) if age < self.cold => Decision::Keep,
+            _ => Decision::Demote,
+        }
+    }
+}
+
+/// A system preference the engine can update through a shared reference.
+///
+/// Only implemented by the live [AtomicSystemStoragePreference]. Archived
+/// preferences may live in a read-only buffer and are updated by
+/// [MigrationEngine::apply_archived] instead.
+pub trait SystemPreference {
+    fn get(&self) -> StoragePreference;
+    fn set(&self, pref: StoragePreference);
+}
+
+impl SystemPreference for AtomicSystemStoragePreference {
+    fn get(&self) -> StoragePreference {
+        self.into()
+    }
+
+    fn set(&self, pref: StoragePreference) {
+        AtomicSystemStoragePreference::set(self, pref)
+    }
+}
+
+/// Number of objects moved by one [MigrationEngine::apply].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MigrationReport {
+    pub promoted: usize,
+    pub demoted: usize,
+}
+
+/// Tracks accesses of objects identified by `K`, e.g. keys or node offsets,
+/// and migrates them according to the policy `P`.
+#[derive(Debug)]
+pub struct MigrationEngine<K, P> {
+    policy: P,
+    classes: StorageClasses,
+    now: u64,
+    stats: HashMap<K, AccessStats>,
+}
+
+impl<K: Hash + Eq, P: MigrationPolicy> MigrationEngine<K, P> {
+    pub fn new(policy: P, classes: StorageClasses) -> Self {
+        MigrationEngine { policy, classes, now: 0, stats: HashMap::new() }
+    }
+
+    /// Starts tracking `object` without recording an access, so that it can
+    /// be demoted even if it is never accessed.
+    pub fn track(&mut self, object: K) {
+        self.stats.entry(object).or_default();
+    }
+
+    /// Records one access of `object`.
+    pub fn record(&mut self, object: K) {
+        self.now += 1;
+        let stats = self.stats.entry(object).or_default();
+        stats.count += 1;
+        stats.last_access = Some(self.now);
+    }
+
+    /// Returns the statistics of `object`.
+    pub fn stats(&self, object: &K) -> Option<&AccessStats> {
+        self.stats.get(object)
+    }
+
+    /// Ends the epoch: moves the system preference of every tracked object
+    /// one class according to the policy. Objects for which `target` returns
+    /// `None` are skipped.
+    pub fn apply<'a, T, F>(&mut self, mut target: F) -> MigrationReport
+    where
+        T: SystemPreference + ?Sized + 'a,
+        F: FnMut(&K) -> Option<&'a T>,
+    {
+        self.migrate(|object, step| {
+            if let Some(pref) = target(object) {
+                if let Some(next) = step(pref.get()) {
+                    pref.set(next);
+                }
+            }
+        })
+    }
+
+    /// Like [MigrationEngine::apply], for the archived preferences `prefs`
+    /// in a writable buffer, e.g. validated by
+    /// [crate::in_place::rkyv_check_value_mut]. `index` returns the position
+    /// of an object in `prefs`; objects without a position or beyond the end
+    /// are skipped.
+    pub fn apply_archived<F>(
+        &mut self,
+        mut prefs: Pin<&mut ArchivedVec<ArchivedAtomicSystemStoragePreference>>,
+        mut index: F,
+    ) -> MigrationReport
+    where
+        F: FnMut(&K) -> Option<usize>,
+    {
+        self.migrate(|object, step| {
+            if let Some(idx) = index(object).filter(|idx| *idx < prefs.len()) {
+                let pref = prefs.as_mut().index_pin(idx);
+                if let Some(next) = step(StoragePreference::from(&*pref)) {
+                    pref.set(next);
+                }
+            }
+        })
+    }
+
+    // Calls `update` with every tracked object and a step function, which
+    // maps the current preference of the object to the next one, if any.
+    fn migrate<F>(&mut self, mut update: F) -> MigrationReport
+    where
+        F: FnMut(&K, &mut dyn FnMut(StoragePreference) -> Option<StoragePreference>),
+    {
+        let mut report = MigrationReport::default();
+        let (policy, classes, now) = (&self.policy, self.classes, self.now);
+        for (object, stats) in self.stats.iter_mut() {
+            update(object, &mut |pref| {
+                let current = pref.or(classes.slowest());
+                let next = match policy.decide(stats, now) {
+                    Decision::Promote => current.lift(),
+                    Decision::Keep => None,
+                    Decision::Demote => current.lower_within(classes),
+                };
+                match next {
+                    Some(next) if next < current => report.promoted += 1,
+                    Some(next) if next > current => report.demoted += 1,
+                    _ => {}
+                }
+                next
+            });
+            stats.count /= 2;
+        }
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::XorShift;
+    use crate::{codec, in_place};
+
+    const KEYS: u32 = 64;
+    const HOT_KEYS: u32 = 8;
+
+    /// Accesses `accesses` keys: 80% go to the first `HOT_KEYS` keys, the
+    /// remaining ones to the first half of the cold keys.
+    fn workload(accesses: usize, seed: u64) -> impl Iterator<Item = u32> {
+        let mut random = XorShift::new(seed);
+        (0..accesses).map(move |_| match random.below(10) {
+            0 | 1 => HOT_KEYS + random.below(((KEYS - HOT_KEYS) / 2) as u64) as u32,
+            _ => random.below(HOT_KEYS as u64) as u32,
+        })
+    }
+
+    fn simulate<P: MigrationPolicy>(policy: P) -> Vec<StoragePreference> {
+        let prefs: Vec<_> = (0..KEYS).map(|_| AtomicSystemStoragePreference::from(StoragePreference::FAST)).collect();
+        let mut engine = MigrationEngine::new(policy, StorageClasses::DEFAULT);
+        (0..KEYS).for_each(|key| engine.track(key));
+        for epoch in 0..8 {
+            for key in workload(1000, epoch + 1) {
+                engine.record(key);
+            }
+            engine.apply(|key| prefs.get(*key as usize));
+        }
+        prefs.iter().map(StoragePreference::from).collect()
+    }
+
+    #[test]
+    fn lfu_promotes_hot_and_demotes_cold_keys() {
+        let prefs = simulate(Lfu { hot: 100, cold: 30 });
+        assert!(prefs[..HOT_KEYS as usize].iter().all(|pref| *pref == StoragePreference::FASTEST), "{:?}", prefs);
+        assert!(prefs[HOT_KEYS as usize..].iter().all(|pref| *pref == StoragePreference::SLOWEST), "{:?}", prefs);
+    }
+
+    #[test]
+    fn lru_promotes_recent_and_demotes_stale_keys() {
+        let prefs = simulate(Lru { hot: 200, cold: 2000 });
+        assert!(prefs[..HOT_KEYS as usize].iter().all(|pref| *pref == StoragePreference::FASTEST), "{:?}", prefs);
+        let never_accessed = &prefs[(HOT_KEYS + (KEYS - HOT_KEYS) / 2) as usize..];
+        assert!(never_accessed.iter().all(|pref| *pref == StoragePreference::SLOWEST), "{:?}", prefs);
+    }
+
+    #[test]
+    fn moves_one_class_per_epoch() {
+        let pref = AtomicSystemStoragePreference::none();
+        let mut engine = MigrationEngine::new(Lfu { hot: 1, cold: 1 }, StorageClasses::new(8));
+        let mut classes = Vec::new();
+        for _ in 0..9 {
+            engine.record("key");
+            engine.apply(|_| Some(&pref));
+            classes.push(StoragePreference::from(&pref).preferred_class().unwrap());
+        }
+        assert_eq!(classes, [6, 5, 4, 3, 2, 1, 0, 0, 0]);
+
+        let report = engine.apply(|_| Some(&pref));
+        assert_eq!(report, MigrationReport { promoted: 0, demoted: 1 });
+        assert_eq!(engine.stats(&"key"), Some(&AccessStats { count: 0, last_access: Some(9) }));
+    }
+
+    #[test]
+    fn migrates_archived_preferences() {
+        let prefs: Vec<_> = (0..4).map(|_| AtomicSystemStoragePreference::none()).collect();
+        let mut bytes = codec::rkyv_serialize_value(&prefs).unwrap();
+        let archived =
+            in_place::rkyv_check_value_mut::<Vec<AtomicSystemStoragePreference>>(Pin::new(&mut bytes[..])).unwrap();
+
+        let mut engine = MigrationEngine::new(Lfu { hot: 1, cold: 1 }, StorageClasses::DEFAULT);
+        engine.record(0);
+        engine.track(1);
+        engine.track(9);
+        // Placing the untouched key in the slowest class is not a demotion.
+        let report = engine.apply_archived(archived, |key| Some(*key));
+        assert_eq!(report, MigrationReport { promoted: 1, demoted: 0 });
+
+        let archived = codec::rkyv_check_value::<Vec<AtomicSystemStoragePreference>>(&bytes).unwrap();
+        let prefs = codec::rkyv_deserialize_value::<Vec<AtomicSystemStoragePreference>>(archived).unwrap();
+        let prefs: Vec<_> = prefs.iter().map(StoragePreference::from).collect();
+        assert_eq!(prefs, [StoragePreference::SLOW, StoragePreference::SLOWEST, StoragePreference::NONE, StoragePreference::NONE]);
+    }
+}
@@ -0,0 +1,149 @@
+//! This module provides round trips of a batch of independent nodes on a
+//! fixed number of threads, as when many dirty nodes are flushed at once.
+//!
+//! The threads are scoped to one batch and take the next node from a shared
+//! counter, so a few large nodes do not leave the other threads idle.
+//! [Throughput] reports the batch in nodes and encoded bytes per second.
+
+use crate::codec::{self, RkyvWriter};
+use crate::error::Result;
+use crate::{Type_A, Type_B};
+
+use std::{
+    cell::RefCell,
+    fmt,
+    sync::atomic::{AtomicUsize, Ordering},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Nodes and encoded bytes processed in `elapsed`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Throughput {
+    pub nodes: usize,
+    pub bytes: usize,
+    pub elapsed: Duration,
+}
+
+impl Throughput {
+    pub fn nodes_per_sec(&self) -> f64 {
+        self.nodes as f64 / self.elapsed.as_secs_f64()
+    }
+
+    pub fn mib_per_sec(&self) -> f64 {
+        self.bytes as f64 / (1024.0 * 1024.0) / self.elapsed.as_secs_f64()
+    }
+}
+
+impl fmt::Display for Throughput {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:>6} ms {:>10.0} nodes/s {:>8.1} MiB/s",
+            self.elapsed.as_millis(),
+            self.nodes_per_sec(),
+            self.mib_per_sec()
+        )
+    }
+}
+
+/// Runs `round_trip` on every node on `threads` threads, at least one, and
+/// sums the encoded sizes it returns. Stops at the first error.
+pub fn round_trips<T, F>(nodes: &[T], threads: usize, round_trip: F) -> Result<Throughput>
+where
+    T: Sync,
+    F: Fn(&T) -> Result<usize> + Sync,
+{
+    let next = AtomicUsize::new(0);
+    let start_time = Instant::now();
+    let bytes = thread::scope(|scope| {
+        let workers: Vec<_> = (0..threads.max(1))
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut bytes = 0;
+                    while let Some(node) = nodes.get(next.fetch_add(1, Ordering::Relaxed)) {
+                        bytes += round_trip(node).inspect_err(|_| {
+                            // Let the other threads run out of nodes.
+                            next.store(nodes.len(), Ordering::Relaxed);
+                        })?;
+                    }
+                    Ok(bytes)
+                })
+            })
+            .collect();
+        workers.into_iter().map(|worker| worker.join().expect("round trip panicked")).sum::<Result<usize>>()
+    })?;
+    Ok(Throughput { nodes: nodes.len(), bytes, elapsed: start_time.elapsed() })
+}
+
+/// Serializes, validates and deserializes every node with rkyv. Each thread
+/// reuses one [RkyvWriter].
+pub fn rkyv_round_trips(nodes: &[Type_A], threads: usize) -> Result<Throughput> {
+    thread_local! {
+        static WRITER: RefCell<RkyvWriter<4096>> = RefCell::new(RkyvWriter::new());
+    }
+    round_trips(nodes, threads, |node| {
+        WRITER.with_borrow_mut(|writer| {
+            let bytes = writer.write(node)?;
+            codec::rkyv_deserialize(codec::rkyv_check(bytes)?)?;
+            Ok(bytes.len())
+        })
+    })
+}
+
+/// Serializes and deserializes every node with bincode.
+pub fn bincode_round_trips(nodes: &[Type_B], threads: usize) -> Result<Throughput> {
+    round_trips(nodes, threads, |node| {
+        let buf = codec::bincode_serialize(node)?;
+        codec::bincode_deserialize(&buf)?;
+        Ok(buf.len())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::sample_entries;
+    use crate::error::Error;
+
+    fn nodes(count: u32) -> Vec<Type_A> {
+        (1..=count).map(|number| Type_A { entries: sample_entries(number, 100) }).collect()
+    }
+
+    #[test]
+    fn every_node_is_encoded_once() {
+        let nodes = nodes(32);
+        let expected: usize = nodes.iter().map(|node| codec::rkyv_serialize(node).unwrap().len()).sum();
+        for threads in [0, 1, 3, 64] {
+            let throughput = rkyv_round_trips(&nodes, threads).unwrap();
+            assert_eq!((throughput.nodes, throughput.bytes), (32, expected), "{} threads", threads);
+        }
+
+        let nodes: Vec<_> = nodes.into_iter().map(|node| Type_B { entries: node.entries }).collect();
+        let expected: usize = nodes.iter().map(|node| codec::bincode_serialize(node).unwrap().len()).sum();
+        assert_eq!(bincode_round_trips(&nodes, 4).unwrap().bytes, expected);
+    }
+
+    #[test]
+    fn round_trips_match_input() {
+        let nodes = nodes(16);
+        let throughput = round_trips(&nodes, 4, |node| {
+            let bytes = codec::rkyv_serialize(node)?;
+            assert_eq!(&codec::rkyv_deserialize(codec::rkyv_check(&bytes)?)?, node);
+            Ok(bytes.len())
+        });
+        assert_eq!(throughput.unwrap().nodes, 16);
+    }
+
+    #[test]
+    fn first_error_stops_batch() {
+        let visited = AtomicUsize::new(0);
+        let err = round_trips(&[0u32; 1000], 4, |_| {
+            visited.fetch_add(1, Ordering::Relaxed);
+            Err(Error::InPlace { reason: "failing on purpose".to_string() })
+        })
+        .unwrap_err();
+        assert_eq!(err.phase(), "in-place update");
+        assert!(visited.load(Ordering::Relaxed) <= 4);
+    }
+}
@@ -0,0 +1,194 @@
+//! This module provides `SharedArchive`, one archived leaf validated once
+//! and then read by many threads without deserializing it.
+//!
+//! The archive lives in an aligned buffer or a mapped file and is shared
+//! through an `Arc`. [concurrent_lookups] runs scattered point lookups against
+//! any shared reader, e.g. a `SharedArchive` or a deserialized `Type_B`
+//! behind a `parking_lot::RwLock`, and reports the lookup throughput.
+
+use crate::codec;
+use crate::cow_bytes::CowBytes;
+use crate::error::Result;
+use crate::stream;
+use crate::{ArchivedType_A, Type_B};
+
+use std::{
+    path::Path,
+    sync::{Arc, Barrier},
+    thread,
+    time::{Duration, Instant},
+};
+
+use memmap2::Mmap;
+use parking_lot::RwLock;
+use rkyv::AlignedVec;
+
+/// Distance between consecutive keys looked up by one thread, prime so that
+/// a thread visits every key unless their number is a multiple of it.
+const LOOKUP_STRIDE: usize = 7919;
+
+enum Buffer {
+    Aligned(AlignedVec),
+    Mapped(Mmap),
+}
+
+/// A validated archive of a `Type_A` leaf, readable from any thread.
+pub struct SharedArchive {
+    // Never modified after validation.
+    buffer: Buffer,
+}
+
+impl SharedArchive {
+    fn new(buffer: Buffer) -> Result<Self> {
+        let shared = SharedArchive { buffer };
+        codec::rkyv_check(shared.bytes())?;
+        Ok(shared)
+    }
+
+    /// Validates an archive written by [codec::rkyv_serialize].
+    pub fn from_bytes(bytes: AlignedVec) -> Result<Self> {
+        Self::new(Buffer::Aligned(bytes))
+    }
+
+    /// Maps and validates the archive in the file at `path`, which must not
+    /// be modified while mapped.
+    pub fn map_file(path: &Path) -> Result<Self> {
+        Self::new(Buffer::Mapped(stream::rkyv_map_file(path)?))
+    }
+
+    fn bytes(&self) -> &[u8] {
+        match &self.buffer {
+            Buffer::Aligned(bytes) => bytes,
+            Buffer::Mapped(map) => map,
+        }
+    }
+
+    /// The archived leaf.
+    pub fn archived(&self) -> &ArchivedType_A {
+        // The buffer was validated by `new` and is immutable since.
+        unsafe { codec::rkyv_access(self.bytes()) }.expect("validated when shared")
+    }
+
+    /// Returns the value of `key`.
+    pub fn get(&self, key: &[u8]) -> Option<&[u8]> {
+        let entries = &self.archived().entries;
+        let idx = entries.binary_search_by(|entry| entry.key.as_ref().cmp(key)).ok()?;
+        entries[idx].value.1.bytes()
+    }
+}
+
+/// Looks up `key` in a deserialized leaf shared between threads.
+pub fn locked_get(node: &RwLock<Type_B>, key: &[u8]) -> Option<usize> {
+    node.read().entries.get(key).map(|(_, value)| value.len())
+}
+
+/// Lookups done by [concurrent_lookups], of which `found` hit a key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Lookups {
+    pub lookups: usize,
+    pub found: usize,
+    pub elapsed: Duration,
+}
+
+impl Lookups {
+    pub fn per_sec(&self) -> f64 {
+        self.lookups as f64 / self.elapsed.as_secs_f64()
+    }
+}
+
+/// Spawns `threads` threads, at least one, which share `reader` and each
+/// look up `lookups` keys spread over `keys`. `lookup` returns the
+/// length of the value found.
+///
+/// The clock starts once all threads are spawned and stops when the last
+/// one finishes its lookups, so spawning and joining are not measured.
+pub fn concurrent_lookups<R, F>(
+    reader: Arc<R>,
+    keys: Arc<[CowBytes]>,
+    threads: usize,
+    lookups: usize,
+    lookup: F,
+) -> Lookups
+where
+    R: Send + Sync + 'static,
+    F: Fn(&R, &[u8]) -> Option<usize> + Send + Copy + 'static,
+{
+    let threads = threads.max(1);
+    let barrier = Arc::new(Barrier::new(threads + 1));
+    let workers: Vec<_> = (0..threads)
+        .map(|thread| {
+            let (reader, keys, barrier) = (Arc::clone(&reader), Arc::clone(&keys), Arc::clone(&barrier));
+            thread::spawn(move || {
+                barrier.wait();
+                // Every thread starts at its own key and strides over the
+                // keys, so that consecutive lookups hit distant keys.
+                let mut index = thread % keys.len();
+                let mut found = 0;
+                for _ in 0..lookups {
+                    found += lookup(&reader, &keys[index]).is_some() as usize;
+                    index = (index + LOOKUP_STRIDE) % keys.len();
+                }
+                (found, Instant::now())
+            })
+        })
+        .collect();
+    barrier.wait();
+    let start_time = Instant::now();
+    let (mut found, mut end_time) = (0, start_time);
+    for worker in workers {
+        let (worker_found, worker_end) = worker.join().expect("reader panicked");
+        found += worker_found;
+        end_time = end_time.max(worker_end);
+    }
+    Lookups { lookups: threads * lookups, found, elapsed: end_time.duration_since(start_time) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::sample_entries;
+    use crate::Type_A;
+
+    fn keys(count: u32) -> Arc<[CowBytes]> {
+        (0..count + 2).map(|number| CowBytes::from(number.to_string().as_bytes())).collect()
+    }
+
+    #[test]
+    fn readers_share_one_archive() {
+        let leaf = Type_A { entries: sample_entries(100, 16) };
+        let bytes = codec::rkyv_serialize(&leaf).unwrap();
+        let path = stream::scratch_path("readers_share_one_archive");
+        stream::rkyv_to_file(&bytes, &path).unwrap();
+
+        for shared in [SharedArchive::from_bytes(bytes).unwrap(), SharedArchive::map_file(&path).unwrap()] {
+            assert_eq!(shared.get(b"7"), Some(&[1u8; 16][..]));
+            assert_eq!(shared.get(b"101"), None);
+            // Keys 0 and 101 are missing.
+            let lookups = concurrent_lookups(Arc::new(shared), keys(100), 4, 1000, |shared, key| {
+                shared.get(key).map(<[u8]>::len)
+            });
+            assert_eq!(lookups.lookups, 4000);
+            assert!(lookups.found > 3000 && lookups.found < 4000, "{:?}", lookups);
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn archive_and_locked_node_agree() {
+        let entries = sample_entries(100, 16);
+        let bytes = codec::rkyv_serialize(&Type_A { entries: entries.clone() }).unwrap();
+        let shared = Arc::new(SharedArchive::from_bytes(bytes).unwrap());
+        let locked = Arc::new(RwLock::new(Type_B { entries }));
+        let archived = concurrent_lookups(shared, keys(100), 3, 500, |shared, key| shared.get(key).map(<[u8]>::len));
+        let deserialized = concurrent_lookups(locked, keys(100), 3, 500, locked_get);
+        assert_eq!((archived.lookups, archived.found), (deserialized.lookups, deserialized.found));
+    }
+
+    #[test]
+    fn invalid_archives_are_not_shared() {
+        let mut bytes = codec::rkyv_serialize(&Type_A { entries: sample_entries(4, 16) }).unwrap();
+        let len = bytes.len();
+        bytes[len - 4..].copy_from_slice(&[0xff; 4]);
+        assert_eq!(SharedArchive::from_bytes(bytes).err().unwrap().phase(), "validate");
+    }
+}
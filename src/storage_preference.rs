@@ -1,7 +1,10 @@
 use serde::{Deserialize, Serialize};
 use speedy::{Readable, Writable};
+use rkyv::bytecheck::CheckBytes;
 use std::{
-    cmp,
+    cmp, fmt,
+    pin::Pin,
+    ptr,
     sync::atomic::{AtomicU8, Ordering},
 };
 
@@ -10,6 +13,12 @@ const FASTEST: u8 = 0;
 const FAST: u8 = 1;
 const SLOW: u8 = 2;
 const SLOWEST: u8 = 3;
+// Only used by `AtomicStoragePreference`, in place of `None`.
+const UNKNOWN: u8 = u8::MAX;
+
+/// The highest number of storage classes. The remaining values of the byte are reserved for
+/// [StoragePreference::NONE] and the unknown state of [AtomicStoragePreference].
+pub const MAX_CLASSES: u8 = NONE;
 
 /// An allocation preference. If a [StoragePreference] other than [StoragePreference::NONE]
 /// is used for an operation, the allocator will try to allocate on that storage class,
@@ -20,23 +29,78 @@ const SLOWEST: u8 = 3;
 /// per-dataset, or the global default.
 ///
 /// The different class constants are vaguely named `FASTEST`, `FAST`, `SLOW`, and `SLOWEST`,
-/// but a [StoragePreference] can also be created with [StoragePreference::new].
+/// but a [StoragePreference] can also be created with [StoragePreference::new]. The constants
+/// describe the default of four classes; deployments with a different number of classes
+/// describe them with [StorageClasses].
 ///
 /// The exact properties of a storage layer depend on the database administrator, who is assumed
 /// to ensure that the vague ordering properties hold for the given deployment.
 ///
 /// This type is not an `Option<u8>`, because it saves one byte per value, and allows the
 /// implementation of convenience methods on itself.
+///
+/// Decoding rejects the byte reserved for the unknown state of
+/// [AtomicStoragePreference], in serde via [TryFrom] and in rkyv via the
+/// [CheckBytes] implementation of the archived preference.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize, Readable, Writable, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize)]
-#[archive(check_bytes)]
+#[serde(try_from = "u8", into = "u8")]
 #[repr(transparent)]
 pub struct StoragePreference(u8);
 
+/// A byte which is neither a storage class nor [StoragePreference::NONE].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvalidStoragePreference(pub u8);
+
+impl fmt::Display for InvalidStoragePreference {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid storage preference {}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidStoragePreference {}
+
+impl TryFrom<u8> for StoragePreference {
+    type Error = InvalidStoragePreference;
+
+    fn try_from(byte: u8) -> Result<Self, InvalidStoragePreference> {
+        if byte == NONE || byte < MAX_CLASSES {
+            Ok(Self(byte))
+        } else {
+            Err(InvalidStoragePreference(byte))
+        }
+    }
+}
+
+impl From<StoragePreference> for u8 {
+    fn from(pref: StoragePreference) -> u8 {
+        pref.0
+    }
+}
+
+impl<C: ?Sized> CheckBytes<C> for ArchivedStoragePreference {
+    type Error = InvalidStoragePreference;
+
+    unsafe fn check_bytes<'a>(value: *const Self, _context: &mut C) -> Result<&'a Self, Self::Error> {
+        // Any byte is a valid `u8`, so only the value needs checking.
+        let byte = *ptr::addr_of!((*value).0);
+        StoragePreference::try_from(byte)?;
+        Ok(&*value)
+    }
+}
+
 impl From<&ArchivedStoragePreference> for StoragePreference {
     fn from(obj: &ArchivedStoragePreference) -> Self {
         StoragePreference(obj.0)
     }
 }
+
+impl ArchivedStoragePreference {
+    /// Overwrites the archived preference in place.
+    pub fn set(self: Pin<&mut Self>, pref: StoragePreference) {
+        // A plain byte, which can be replaced without moving anything.
+        unsafe { self.get_unchecked_mut() }.0 = pref.0;
+    }
+}
 impl StoragePreference {
     /// No preference, any other preference overrides this.
     pub const NONE: Self = Self(NONE);
@@ -46,13 +110,14 @@ impl StoragePreference {
     pub const FAST: Self = Self(FAST);
     /// The third-fastest, or second-slowest, storage class (2).
     pub const SLOW: Self = Self(SLOW);
-    /// The slowest storage class (3).
+    /// The slowest storage class (3) of the default [StorageClasses].
     pub const SLOWEST: Self = Self(SLOWEST);
 
     /// Construct a new [StoragePreference], for a given class.
-    /// Panics if `class > 3`.
+    /// Panics if `class >= MAX_CLASSES`. Use [StorageClasses::preference] to check the class
+    /// against the configured classes.
     pub const fn new(class: u8) -> Self {
-        assert!(class <= 3);
+        assert!(class < MAX_CLASSES);
         Self(class)
     }
 
@@ -86,7 +151,7 @@ impl StoragePreference {
         self.0
     }
     pub(crate) const fn from_u8(u: u8) -> Self {
-        debug_assert!(u == u8::MAX - 1 || u <= 3);
+        debug_assert!(u == NONE || u < MAX_CLASSES);
         Self(u)
     }
 
@@ -101,15 +166,92 @@ impl StoragePreference {
         }
     }
 
-    pub(crate) fn lower(self) -> Option<StoragePreference> {
+    /// Moves the preference one class slower, bounded by the slowest of the
+    /// [StorageClasses::DEFAULT] classes.
+    pub fn lower(self) -> Option<StoragePreference> {
+        self.lower_within(StorageClasses::DEFAULT)
+    }
+
+    /// Like [StoragePreference::lower], but bounded by the slowest of `classes`.
+    /// Returns `None` for [StoragePreference::NONE] and for a class beyond `classes`,
+    /// which would otherwise be moved to a faster class.
+    pub fn lower_within(self, classes: StorageClasses) -> Option<StoragePreference> {
         match self {
             Self::NONE => None,
-            Self::SLOWEST => Some(Self::SLOWEST),
-            _ => Some(Self(self.0.saturating_add(1))),
+            _ if self > classes.slowest() => None,
+            _ if self == classes.slowest() => Some(self),
+            _ => Some(Self(self.0 + 1)),
         }
     }
 }
 
+/// The number of storage classes of a deployment, from class 0, the fastest, to class
+/// `count - 1`, the slowest. Preferences stay one byte regardless of the count.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+#[serde(try_from = "u8", into = "u8")]
+pub struct StorageClasses(u8);
+
+impl StorageClasses {
+    /// The four classes [StoragePreference::FASTEST] to [StoragePreference::SLOWEST].
+    pub const DEFAULT: Self = Self(4);
+
+    /// Describes `count` storage classes.
+    /// Panics if `count` is 0 or larger than [MAX_CLASSES].
+    pub const fn new(count: u8) -> Self {
+        assert!(count > 0 && count <= MAX_CLASSES);
+        Self(count)
+    }
+
+    /// The number of classes.
+    pub const fn count(self) -> u8 {
+        self.0
+    }
+
+    /// The slowest class.
+    pub const fn slowest(self) -> StoragePreference {
+        StoragePreference(self.0 - 1)
+    }
+
+    /// Returns the preference for `class`, or `None` if there is no such class.
+    pub fn preference(self, class: u8) -> Option<StoragePreference> {
+        (class < self.0).then_some(StoragePreference(class))
+    }
+
+    /// Returns whether `pref` is one of the classes. [StoragePreference::NONE] is not.
+    pub fn contains(self, pref: StoragePreference) -> bool {
+        pref.0 < self.0
+    }
+
+    /// All classes, from the fastest to the slowest.
+    pub fn iter(self) -> impl Iterator<Item = StoragePreference> {
+        (0..self.0).map(StoragePreference)
+    }
+}
+
+impl Default for StorageClasses {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+impl TryFrom<u8> for StorageClasses {
+    type Error = String;
+
+    fn try_from(count: u8) -> Result<Self, String> {
+        if count > 0 && count <= MAX_CLASSES {
+            Ok(Self(count))
+        } else {
+            Err(format!("number of storage classes must be within 1..={}, got {}", MAX_CLASSES, count))
+        }
+    }
+}
+
+impl From<StorageClasses> for u8 {
+    fn from(classes: StorageClasses) -> u8 {
+        classes.0
+    }
+}
+
 // Ordered by `strictness`, so 0 < 1 < 2 < 3 < None.
 // Implemented separately instead of derived, to comment
 // and error on some changes to struct items.
@@ -124,7 +266,30 @@ impl PartialOrd for StoragePreference {
 #[archive(check_bytes)]
 /// An atomic version of [StoragePreference], replacing a RwLock<Option<StoragePreference>> by
 /// using the additional variant "Unknown" in place of None.
-pub struct AtomicStoragePreference(AtomicU8);
+///
+/// The archived form keeps the [AtomicU8], so it can be loaded and updated atomically within
+/// the archive. Updates need a writable buffer, e.g. a writable mapping of the node.
+pub struct AtomicStoragePreference(#[with(rkyv::with::Atomic)] AtomicU8);
+
+fn load_option(pref: &AtomicU8) -> Option<StoragePreference> {
+    match pref.load(Ordering::SeqCst) {
+        UNKNOWN => None,
+        v => Some(StoragePreference(v)),
+    }
+}
+
+fn upgrade(pref: &AtomicU8, other: StoragePreference) {
+    pref.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |p| {
+        if p != UNKNOWN {
+            let mut sp = StoragePreference(p);
+            sp.upgrade(other);
+            Some(sp.0)
+        } else {
+            Some(p)
+        }
+    })
+    .unwrap();
+}
 
 #[allow(missing_docs)]
 impl AtomicStoragePreference {
@@ -133,17 +298,11 @@ impl AtomicStoragePreference {
     }
 
     pub const fn unknown() -> Self {
-        Self(AtomicU8::new(u8::MAX))
+        Self(AtomicU8::new(UNKNOWN))
     }
 
     pub fn as_option(&self) -> Option<StoragePreference> {
-        let v = self.0.load(Ordering::SeqCst);
-
-        if v == u8::MAX {
-            None
-        } else {
-            Some(StoragePreference(v))
-        }
+        load_option(&self.0)
     }
 
     pub fn unwrap_or_none(&self) -> StoragePreference {
@@ -155,32 +314,22 @@ impl AtomicStoragePreference {
     }
 
     pub fn invalidate(&self) {
-        self.0.store(u8::MAX, Ordering::SeqCst);
+        self.0.store(UNKNOWN, Ordering::SeqCst);
     }
 
     pub fn upgrade(&self, other: StoragePreference) {
-        self.0
-            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |p| {
-                if p != u8::MAX {
-                    let mut sp = StoragePreference(p);
-                    sp.upgrade(other);
-                    Some(sp.0)
-                } else {
-                    Some(p)
-                }
-            })
-            .unwrap();
+        upgrade(&self.0, other)
     }
 
     pub fn upgrade_atomic(&self, other: &AtomicStoragePreference) {
         self.0
             .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |p| {
                 // only track changes if in a known state
-                if p != u8::MAX {
+                if p != UNKNOWN {
                     let mut sp = StoragePreference(p);
 
                     let other_p = other.0.load(Ordering::SeqCst);
-                    if other_p != u8::MAX {
+                    if other_p != UNKNOWN {
                         sp.upgrade(StoragePreference(other_p));
                     }
                     Some(sp.0)
@@ -192,6 +341,39 @@ impl AtomicStoragePreference {
     }
 }
 
+/// Updates take `Pin<&mut Self>` like the projections of [crate::in_place],
+/// so that they can only reach a writable buffer, e.g. through
+/// [crate::in_place::rkyv_check_value_mut]. Validated archives in shared or
+/// read-only mapped buffers stay read-only.
+#[allow(missing_docs)]
+impl ArchivedAtomicStoragePreference {
+    pub fn as_option(&self) -> Option<StoragePreference> {
+        load_option(&self.0)
+    }
+
+    pub fn unwrap_or_none(&self) -> StoragePreference {
+        self.as_option().unwrap_or(StoragePreference::NONE)
+    }
+
+    pub fn set(self: Pin<&mut Self>, pref: StoragePreference) {
+        self.0.store(pref.0, Ordering::SeqCst);
+    }
+
+    pub fn invalidate(self: Pin<&mut Self>) {
+        self.0.store(UNKNOWN, Ordering::SeqCst);
+    }
+
+    pub fn upgrade(self: Pin<&mut Self>, other: StoragePreference) {
+        upgrade(&self.0, other)
+    }
+}
+
+impl From<&ArchivedAtomicStoragePreference> for AtomicStoragePreference {
+    fn from(obj: &ArchivedAtomicStoragePreference) -> Self {
+        AtomicStoragePreference(AtomicU8::new(obj.0.load(Ordering::SeqCst)))
+    }
+}
+
 impl Clone for AtomicStoragePreference {
     fn clone(&self) -> Self {
         AtomicStoragePreference(AtomicU8::new(self.0.load(Ordering::SeqCst)))
@@ -214,9 +396,11 @@ impl Default for AtomicStoragePreference {
 /// automated migration policy, in contrast to the lower bound by
 /// [StoragePreference]. Acts as a neutral element when set to
 /// `None`.
+///
+/// Like [AtomicStoragePreference], the archived form keeps the [AtomicU8].
 #[derive(Debug, serde::Serialize, serde::Deserialize, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize)]
 #[archive(check_bytes)]
-pub struct AtomicSystemStoragePreference(AtomicU8);
+pub struct AtomicSystemStoragePreference(#[with(rkyv::with::Atomic)] AtomicU8);
 
 impl Clone for AtomicSystemStoragePreference {
     fn clone(&self) -> Self {
@@ -272,7 +456,7 @@ impl AtomicSystemStoragePreference {
     pub fn weak_bound(&self, prf: &StoragePreference) -> StoragePreference {
         match self.0.load(Ordering::Relaxed) {
             NONE => *prf,
-            lvl @ 0..=3 => {
+            lvl if lvl < MAX_CLASSES => {
                 if lvl > prf.as_u8() {
                     *prf
                 } else {
@@ -288,6 +472,26 @@ impl AtomicSystemStoragePreference {
     }
 }
 
+impl ArchivedAtomicSystemStoragePreference {
+    /// Overwrites the archived preference in place, see
+    /// [ArchivedAtomicStoragePreference].
+    pub fn set(self: Pin<&mut Self>, pref: StoragePreference) {
+        self.0.store(pref.as_u8(), Ordering::SeqCst);
+    }
+}
+
+impl From<&ArchivedAtomicSystemStoragePreference> for AtomicSystemStoragePreference {
+    fn from(obj: &ArchivedAtomicSystemStoragePreference) -> Self {
+        Self(AtomicU8::new(obj.0.load(Ordering::Relaxed)))
+    }
+}
+
+impl From<&ArchivedAtomicSystemStoragePreference> for StoragePreference {
+    fn from(obj: &ArchivedAtomicSystemStoragePreference) -> StoragePreference {
+        StoragePreference::from_u8(obj.0.load(Ordering::Relaxed))
+    }
+}
+
 impl PartialEq for AtomicSystemStoragePreference {
     fn eq(&self, other: &Self) -> bool {
         self.0.load(Ordering::SeqCst) == other.0.load(Ordering::SeqCst)
@@ -296,7 +500,7 @@ impl PartialEq for AtomicSystemStoragePreference {
 
 #[cfg(test)]
 mod tests {
-    use super::{AtomicSystemStoragePreference, StoragePreference};
+    use super::{AtomicSystemStoragePreference, StorageClasses, StoragePreference, MAX_CLASSES};
 
     #[test]
     fn pref_choose_faster() {
@@ -355,4 +559,55 @@ mod tests {
             StoragePreference::SLOW
         );
     }
+
+    #[test]
+    fn lift_lower_bounds() {
+        use super::StoragePreference as S;
+        assert_eq!(S::FASTEST.lift(), Some(S::FASTEST));
+        assert_eq!(S::SLOW.lift(), Some(S::FAST));
+        assert_eq!(S::NONE.lift(), None);
+        assert_eq!(S::SLOW.lower(), Some(S::SLOWEST));
+        assert_eq!(S::SLOWEST.lower(), Some(S::SLOWEST));
+        assert_eq!(S::NONE.lower(), None);
+
+        let classes = StorageClasses::new(8);
+        assert_eq!(S::SLOWEST.lower_within(classes), Some(S::new(4)));
+        assert_eq!(S::new(6).lower_within(classes), Some(S::new(7)));
+        assert_eq!(S::new(7).lower_within(classes), Some(S::new(7)));
+        assert_eq!(S::new(7).lower_within(StorageClasses::DEFAULT), None);
+        assert_eq!(S::new(7).lift(), Some(S::new(6)));
+    }
+
+    #[test]
+    fn more_classes() {
+        use super::StoragePreference as S;
+        let classes = StorageClasses::new(8);
+        assert_eq!(classes.iter().count(), 8);
+        assert_eq!(classes.slowest(), S::new(7));
+        assert_eq!(classes.preference(7), Some(S::new(7)));
+        assert_eq!(classes.preference(8), None);
+        assert!(classes.contains(S::new(7)));
+        assert!(!classes.contains(S::NONE));
+        assert!(!StorageClasses::DEFAULT.contains(S::new(4)));
+
+        // NONE keeps losing against every class.
+        assert_eq!(S::choose_faster(S::NONE, S::new(MAX_CLASSES - 1)), S::new(MAX_CLASSES - 1));
+        assert_eq!(S::new(7).or(S::FAST), S::new(7));
+        assert!(S::new(7) < S::NONE);
+
+        let bound = AtomicSystemStoragePreference::from(S::new(6));
+        assert_eq!(bound.weak_bound(&S::new(7)), S::new(6));
+        assert_eq!(bound.weak_bound(&S::new(5)), S::new(5));
+
+        let err = StorageClasses::try_from(0).unwrap_err();
+        assert_eq!(err, "number of storage classes must be within 1..=254, got 0");
+        assert!(StorageClasses::try_from(MAX_CLASSES + 1).is_err());
+        assert_eq!(bincode::serialize(&classes).unwrap(), vec![8]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn new_rejects_none() {
+        StoragePreference::new(MAX_CLASSES);
+    }
 }
@@ -0,0 +1,266 @@
+//! This module provides file- and pipe-backed variants of the codecs.
+//!
+//! bincode streams the node through an `io::Write` and reads it back through
+//! an `io::Read`, buffered by a `BufWriter`/`BufReader` of configurable size.
+//! rkyv instead writes the whole archive in one go and maps the file back into
+//! memory, so the archived node is accessed without reading it first. Nodes
+//! too large to hold their archive in memory as well are streamed into the
+//! file by [rkyv_serialize_to_file].
+//!
+//! Like the codec layer, bincode reads are bounded by the length of their
+//! input, so a corrupted length prefix is reported instead of allocating
+//! arbitrary amounts of memory. The module is independent of the node types
+//! and used by every scenario binary.
+
+use crate::archive_format::ArchiveFormat;
+use crate::error::{Codec, Error, Result};
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+    process, thread,
+};
+
+use memmap2::{Mmap, MmapMut};
+use rkyv::ser::serializers::{
+    AllocScratch, CompositeSerializer, CompositeSerializerError, SharedSerializeMap, WriteSerializer,
+};
+use rkyv::ser::Serializer;
+use bincode::Options;
+use serde::{de::DeserializeOwned, Serialize};
+
+/// Buffer size used by `BufWriter` and `BufReader` unless specified otherwise.
+pub const DEFAULT_BUFFER_SIZE: usize = 8 * 1024;
+
+fn bincode_error(e: bincode::ErrorKind, phase: fn(Codec, String) -> Error) -> Error {
+    match e {
+        bincode::ErrorKind::Io(e) => Error::Io(e),
+        other => phase(Codec::Bincode, other.to_string()),
+    }
+}
+
+/// Streams `data` into `writer` through a buffer of `buffer_size` bytes.
+pub fn bincode_write<T: Serialize, W: Write>(data: &T, writer: W, buffer_size: usize) -> Result<()> {
+    let mut writer = BufWriter::with_capacity(buffer_size, writer);
+    bincode::serialize_into(&mut writer, data)
+        .map_err(|e| bincode_error(*e, |codec, message| Error::Serialize { codec, message }))?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Reads a node of at most `limit` bytes from `reader` through a buffer of
+/// `buffer_size` bytes. Lengths within the node which exceed `limit` are
+/// rejected before allocating anything for them.
+pub fn bincode_read<T: DeserializeOwned, R: Read>(reader: R, buffer_size: usize, limit: u64) -> Result<T> {
+    let reader = BufReader::with_capacity(buffer_size, reader);
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+        .with_limit(limit)
+        .deserialize_from(reader)
+        .map_err(|e| match *e {
+            bincode::ErrorKind::Io(e) => Error::Io(e),
+            other => Error::bincode_deserialize(other, limit),
+        })
+}
+
+/// Streams `data` into a new file at `path`.
+pub fn bincode_to_file<T: Serialize>(data: &T, path: &Path, buffer_size: usize) -> Result<()> {
+    bincode_write(data, File::create(path)?, buffer_size)
+}
+
+/// Reads a node from the file at `path`, bounded by the length of the file.
+pub fn bincode_from_file<T: DeserializeOwned>(path: &Path, buffer_size: usize) -> Result<T> {
+    let file = File::open(path)?;
+    let limit = file.metadata()?.len();
+    bincode_read(file, buffer_size, limit)
+}
+
+/// Streams `data` through an anonymous pipe. A second thread writes while
+/// the calling thread reads, so the pipe never has to hold the whole node.
+/// The reader is bounded by the encoded length, which the writer announces
+/// up front like a framing protocol would.
+pub fn bincode_through_pipe<T>(data: &T, buffer_size: usize) -> Result<T>
+where
+    T: Serialize + DeserializeOwned + Sync,
+{
+    let limit = bincode::serialized_size(data)
+        .map_err(|e| bincode_error(*e, |codec, message| Error::Serialize { codec, message }))?;
+    let (reader, writer) = io::pipe()?;
+    thread::scope(|scope| {
+        let written = scope.spawn(move || bincode_write(data, writer, buffer_size));
+        let read = bincode_read(reader, buffer_size, limit);
+        match (written.join().expect("pipe writer panicked"), read) {
+            (Ok(()), read) => read,
+            // The reader gave up first and closed the pipe.
+            (Err(Error::Io(e)), Err(read)) if e.kind() == io::ErrorKind::BrokenPipe => Err(read),
+            (Err(write), _) => Err(write),
+        }
+    })
+}
+
+/// Writes an encoded archive to a new file at `path` in a single call.
+pub fn rkyv_to_file(bytes: &[u8], path: &Path) -> Result<()> {
+    let mut file = File::create(path)?;
+    file.write_all(bytes)?;
+    file.sync_data()?;
+    Ok(())
+}
+
+/// The serializer of [rkyv_serialize_to_file].
+pub type FileSerializer = CompositeSerializer<WriteSerializer<BufWriter<File>>, AllocScratch, SharedSerializeMap>;
+
+/// Serializes `data` into a new file at `path` through a buffer of
+/// `buffer_size` bytes, without holding the archive in memory, and returns
+/// the length of the archive. `max_len` is an upper bound of the length,
+/// e.g. `Type_A::rkyv_size`, and is checked up front, as rkyv panics once a
+/// relative pointer overflows.
+pub fn rkyv_serialize_to_file<T>(data: &T, max_len: usize, path: &Path, buffer_size: usize) -> Result<u64>
+where
+    T: rkyv::Serialize<FileSerializer>,
+{
+    ArchiveFormat::CURRENT.check_size(max_len)?;
+    let writer = BufWriter::with_capacity(buffer_size, File::create(path)?);
+    let mut serializer =
+        CompositeSerializer::new(WriteSerializer::new(writer), AllocScratch::default(), SharedSerializeMap::new());
+    serializer.serialize_value(data).map_err(|e| match e {
+        CompositeSerializerError::SerializerError(e) => Error::Io(e),
+        e => Error::Serialize { codec: Codec::Rkyv, message: e.to_string() },
+    })?;
+    let writer = serializer.into_serializer();
+    let len = writer.pos() as u64;
+    let file = writer.into_inner().into_inner().map_err(|e| e.into_error())?;
+    file.sync_data()?;
+    Ok(len)
+}
+
+/// Maps the file at `path` into memory. Mappings are page aligned, so the
+/// result can be passed to [crate::codec::rkyv_check] directly.
+pub fn rkyv_map_file(path: &Path) -> Result<Mmap> {
+    let file = File::open(path)?;
+    // The file is private to the benchmark and not modified while mapped.
+    Ok(unsafe { Mmap::map(&file)? })
+}
+
+/// Maps the file at `path` into memory for reading and writing. Changes are
+/// written back to the file, at the latest by [MmapMut::flush].
+pub fn rkyv_map_file_mut(path: &Path) -> Result<MmapMut> {
+    let file = OpenOptions::new().read(true).write(true).open(path)?;
+    // As above, the file is private to the benchmark.
+    Ok(unsafe { MmapMut::map_mut(&file)? })
+}
+
+/// Returns a path in the temporary directory which is unique to this process
+/// and `name`.
+pub fn scratch_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("serde_vs_rkyv_{}_{}", process::id(), name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A node of the shape the scenarios stream: a list of byte strings.
+    #[derive(
+        Debug,
+        PartialEq,
+        serde::Serialize,
+        serde::Deserialize,
+        rkyv::Archive,
+        rkyv::Serialize,
+        rkyv::Deserialize,
+    )]
+    #[archive(check_bytes)]
+    struct Node {
+        entries: Vec<Vec<u8>>,
+    }
+
+    fn sample_node() -> Node {
+        Node { entries: (0..64u32).map(|i| vec![i as u8; 1000]).collect() }
+    }
+
+    fn rkyv_bytes(data: &Node) -> Vec<u8> {
+        rkyv::to_bytes::<_, 256>(data).unwrap().into_vec()
+    }
+
+    #[test]
+    fn bincode_file_round_trip() {
+        let data = sample_node();
+        let path = scratch_path("bincode_file_round_trip");
+        for buffer_size in [1, 64, DEFAULT_BUFFER_SIZE] {
+            bincode_to_file(&data, &path, buffer_size).unwrap();
+            let read: Node = bincode_from_file(&path, buffer_size).unwrap();
+            assert_eq!(read, data);
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn bincode_pipe_round_trip() {
+        let data = sample_node();
+        for buffer_size in [1, 64, DEFAULT_BUFFER_SIZE] {
+            let read = bincode_through_pipe(&data, buffer_size).unwrap();
+            assert_eq!(read, data);
+        }
+    }
+
+    #[test]
+    fn rkyv_file_round_trip() {
+        let data = sample_node();
+        let path = scratch_path("rkyv_file_round_trip");
+        rkyv_to_file(&rkyv_bytes(&data), &path).unwrap();
+        let map = rkyv_map_file(&path).unwrap();
+        let archived = rkyv::check_archived_root::<Node>(&map).unwrap();
+        let read: Node = rkyv::Deserialize::deserialize(archived, &mut rkyv::Infallible).unwrap();
+        assert_eq!(read, data);
+        drop(map);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn rkyv_streamed_file_round_trip() {
+        let data = sample_node();
+        let bytes = rkyv_bytes(&data);
+        let path = scratch_path("rkyv_streamed_file_round_trip");
+        for buffer_size in [1, 64, DEFAULT_BUFFER_SIZE] {
+            let len = rkyv_serialize_to_file(&data, bytes.len(), &path, buffer_size).unwrap();
+            let map = rkyv_map_file(&path).unwrap();
+            assert_eq!(map[..], bytes[..]);
+            assert_eq!(map.len() as u64, len);
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn corrupted_files_are_rejected() {
+        let data = sample_node();
+        let bytes = bincode::serialize(&data).unwrap();
+        let path = scratch_path("corrupted_files_are_rejected");
+        for len in [0, 7, 8, bytes.len() / 2, bytes.len() - 1] {
+            std::fs::write(&path, &bytes[..len]).unwrap();
+            assert!(bincode_from_file::<Node>(&path, DEFAULT_BUFFER_SIZE).is_err(), "truncated to {}", len);
+        }
+
+        // The entry count, then the length of the first entry.
+        for offset in [0, 8] {
+            let mut corrupted = bytes.clone();
+            corrupted[offset..offset + 8].copy_from_slice(&u64::MAX.to_le_bytes());
+            std::fs::write(&path, &corrupted).unwrap();
+            let err = bincode_from_file::<Node>(&path, DEFAULT_BUFFER_SIZE).err().unwrap();
+            assert_eq!(err.phase(), "size limit", "{}", err);
+        }
+
+        let err = bincode_read::<Node, _>(&bytes[..], DEFAULT_BUFFER_SIZE, bytes.len() as u64 - 1).err().unwrap();
+        assert_eq!(err.phase(), "size limit");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn missing_file_is_io_error() {
+        let path = scratch_path("missing_file_is_io_error");
+        let err = bincode_from_file::<Node>(&path, DEFAULT_BUFFER_SIZE).err().unwrap();
+        assert_eq!(err.phase(), "io");
+        assert_eq!(rkyv_map_file(&path).err().unwrap().phase(), "io");
+    }
+}
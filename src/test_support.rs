@@ -0,0 +1,50 @@
+//! This module provides the fixtures shared by the unit tests of the other
+//! modules: sample leaf entries and a seeded pseudo-random generator, so that
+//! every run checks the same inputs.
+
+use crate::cow_bytes::{CowBytes, SlicedCowBytes};
+use crate::key_info::KeyInfo;
+use crate::storage_preference::StoragePreference;
+
+use std::collections::BTreeMap;
+
+/// Builds the entries of a leaf with `count` entries, keyed by the decimal
+/// numbers `1..=count`, whose values are `value_len` bytes long.
+pub fn sample_entries(count: u32, value_len: usize) -> BTreeMap<CowBytes, (KeyInfo, SlicedCowBytes)> {
+    let value = CowBytes::from(vec![1u8; value_len]);
+    (1..=count)
+        .map(|number| {
+            (
+                CowBytes::from(number.to_string().as_bytes()),
+                (KeyInfo::new(StoragePreference::new(1)), SlicedCowBytes::from(value.clone())),
+            )
+        })
+        .collect()
+}
+
+/// xorshift64*, good enough to spread test inputs over their range.
+#[derive(Debug, Clone)]
+pub struct XorShift {
+    state: u64,
+}
+
+impl XorShift {
+    /// A generator starting from `seed`. A zero seed is replaced by one, as
+    /// the generator would only ever return zero otherwise.
+    pub fn new(seed: u64) -> Self {
+        XorShift { state: seed.max(1) }
+    }
+
+    /// Returns the next number of the sequence.
+    pub fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    /// Returns the next number of the sequence reduced to `0..bound`.
+    pub fn below(&mut self, bound: u64) -> u64 {
+        self.next_u64() % bound
+    }
+}
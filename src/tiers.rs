@@ -0,0 +1,179 @@
+//! This module provides `TierRegistry`, which describes the storage tier
+//! behind each storage class.
+//!
+//! The registry is read from a TOML file with one `[[tier]]` table per class,
+//! ordered from the fastest (class 0) to the slowest:
+//!
+//! ```toml
+//! [[tier]]
+//! name = "nvme"
+//! latency_us = 20
+//! bandwidth_mib_s = 3000
+//! capacity_gib = 1024
+//! codec = "rkyv"
+//! block_size = 4096
+//! ```
+
+use crate::codec::Codec;
+use crate::error::{Error, Result};
+use crate::storage_preference::{StorageClasses, StoragePreference, MAX_CLASSES};
+
+use std::{fs, path::Path};
+
+/// Properties of the storage tier of one storage class.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Tier {
+    pub name: String,
+    /// Expected access latency in microseconds.
+    pub latency_us: u64,
+    /// Expected sequential bandwidth in MiB/s.
+    pub bandwidth_mib_s: u64,
+    pub capacity_gib: u64,
+    /// Codec used for nodes stored on this tier.
+    pub codec: Codec,
+    /// Size of the blocks nodes are padded to, a power of two.
+    pub block_size: usize,
+}
+
+impl Tier {
+    fn new(name: &str, latency_us: u64, bandwidth_mib_s: u64, capacity_gib: u64, codec: Codec) -> Self {
+        Tier { name: name.to_string(), latency_us, bandwidth_mib_s, capacity_gib, codec, block_size: 4096 }
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    tier: Vec<Tier>,
+}
+
+/// The tiers of all storage classes, indexed by class.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TierRegistry {
+    tiers: Vec<Tier>,
+}
+
+impl TierRegistry {
+    /// Creates a registry with `tiers[class]` describing `class`.
+    pub fn new(tiers: Vec<Tier>) -> Result<Self> {
+        let invalid = |message: String| Err(Error::Config { message });
+        if tiers.is_empty() || tiers.len() > MAX_CLASSES as usize {
+            return invalid(format!("expected 1 to {} tiers, got {}", MAX_CLASSES, tiers.len()));
+        }
+        for (class, tier) in tiers.iter().enumerate() {
+            if !tier.block_size.is_power_of_two() {
+                return invalid(format!("block size {} of tier {} is not a power of two", tier.block_size, class));
+            }
+            if tiers[..class].iter().any(|other| other.name == tier.name) {
+                return invalid(format!("tier name {:?} is used twice", tier.name));
+            }
+        }
+        Ok(TierRegistry { tiers })
+    }
+
+    /// Parses a registry from the TOML in `text`.
+    pub fn from_toml(text: &str) -> Result<Self> {
+        let config: Config = toml::from_str(text).map_err(|e| Error::Config { message: e.to_string() })?;
+        Self::new(config.tier)
+    }
+
+    /// Reads a registry from the TOML file at `path`.
+    pub fn load(path: &Path) -> Result<Self> {
+        Self::from_toml(&fs::read_to_string(path)?)
+    }
+
+    /// Encodes the registry as TOML, as read by [TierRegistry::from_toml].
+    pub fn to_toml(&self) -> String {
+        toml::to_string(&Config { tier: self.tiers.clone() }).expect("tiers are representable in TOML")
+    }
+
+    /// The storage classes described by this registry.
+    pub fn classes(&self) -> StorageClasses {
+        StorageClasses::new(self.tiers.len() as u8)
+    }
+
+    /// Returns the tier of `class`.
+    pub fn tier(&self, class: u8) -> Option<&Tier> {
+        self.tiers.get(class as usize)
+    }
+
+    /// Returns the tier `pref` asks for, or `None` for
+    /// [StoragePreference::NONE] and classes without a tier.
+    pub fn for_preference(&self, pref: StoragePreference) -> Option<&Tier> {
+        self.tier(pref.preferred_class()?)
+    }
+
+    /// Returns the class of the tier called `name`.
+    pub fn class_of(&self, name: &str) -> Option<StoragePreference> {
+        let class = self.tiers.iter().position(|tier| tier.name == name)?;
+        self.classes().preference(class as u8)
+    }
+
+    /// The tiers from the fastest to the slowest class.
+    pub fn iter(&self) -> impl Iterator<Item = (StoragePreference, &Tier)> {
+        self.classes().iter().zip(self.tiers.iter())
+    }
+}
+
+impl Default for TierRegistry {
+    /// Four tiers matching [StorageClasses::DEFAULT].
+    fn default() -> Self {
+        TierRegistry {
+            tiers: vec![
+                Tier::new("nvme", 20, 3000, 1024, Codec::Rkyv),
+                Tier::new("ssd", 100, 500, 4096, Codec::Rkyv),
+                Tier::new("hdd", 5000, 200, 16384, Codec::Bincode),
+                Tier::new("remote", 50000, 100, 65536, Codec::Bincode),
+            ],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn toml_round_trip() {
+        let registry = TierRegistry::default();
+        assert_eq!(registry.classes(), StorageClasses::DEFAULT);
+        assert_eq!(TierRegistry::from_toml(&registry.to_toml()).unwrap(), registry);
+    }
+
+    #[test]
+    fn query_by_preference() {
+        let mut text = TierRegistry::default().to_toml();
+        for name in ["pmem", "nvme-remote", "ssd-remote", "tape"] {
+            text.push_str(&format!(
+                "\n[[tier]]\nname = {:?}\nlatency_us = 1\nbandwidth_mib_s = 1\ncapacity_gib = 1\ncodec = \"bincode\"\nblock_size = 65536\n",
+                name
+            ));
+        }
+        let registry = TierRegistry::from_toml(&text).unwrap();
+        assert_eq!(registry.classes(), StorageClasses::new(8));
+        assert_eq!(registry.for_preference(StoragePreference::FASTEST).unwrap().codec, Codec::Rkyv);
+        assert_eq!(registry.for_preference(StoragePreference::new(7)).unwrap().name, "tape");
+        assert_eq!(registry.for_preference(StoragePreference::new(8)), None);
+        assert_eq!(registry.for_preference(StoragePreference::NONE), None);
+        assert_eq!(registry.class_of("tape"), Some(StoragePreference::new(7)));
+        assert_eq!(registry.iter().count(), 8);
+    }
+
+    #[test]
+    fn invalid_configs() {
+        let tier = "[[tier]]\nname = \"a\"\nlatency_us = 1\nbandwidth_mib_s = 1\ncapacity_gib = 1\ncodec = \"rkyv\"\n";
+        for text in [
+            String::new(),
+            format!("{}block_size = 4096\n{}block_size = 4096\n", tier, tier),
+            format!("{}block_size = 4000\n", tier),
+            format!("{}block_size = 4096\ncompression = true\n", tier),
+            tier.replace("rkyv", "json") + "block_size = 4096\n",
+            tier.to_string(),
+        ] {
+            assert_eq!(TierRegistry::from_toml(&text).unwrap_err().phase(), "config", "{}", text);
+        }
+        let err = TierRegistry::load(Path::new("/nonexistent/tiers.toml")).unwrap_err();
+        assert_eq!(err.phase(), "io");
+    }
+}
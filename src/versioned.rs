@@ -0,0 +1,313 @@
+//! This module provides versioned rkyv archives of leaves.
+//!
+//! rkyv archives carry no description of their layout, so an archive written
+//! with an older layout of `Type_A` or [KeyInfo] fails validation, or worse,
+//! validates as something else. A versioned archive therefore stores a
+//! [VersionTag] as its root, which holds the format version and the position
+//! of the node. The node itself is archived in the layout of that version:
+//!
+//! | version | node                                            |
+//! |---------|-------------------------------------------------|
+//! | 1       | [LegacyLeaf], with one byte per key info        |
+//! | 2       | `Type_A`, with the optional fields of [KeyInfo] |
+//!
+//! Archives written before the tag was introduced are still read: they are
+//! tried as the current layout first and as version 1 after that.
+//! [ArchivedNode::upgrade] migrates a node of any version to the current
+//! `Type_A`, and [rkyv_migrate] rewrites a whole archive.
+//!
+//! The archives in `fixtures/golden` were written by earlier versions and
+//! must stay readable.
+//!
+//! [KeyInfo]: crate::key_info::KeyInfo
+
+use crate::codec::{self, Codec};
+use crate::cow_bytes::ArchivedSlicedCowBytes;
+use crate::error::{Error, Result};
+use crate::leaf::{ArchivedLegacyLeaf, LegacyLeaf};
+use crate::{ArchivedType_A, Type_A};
+
+use rkyv::ser::{serializers::AllocSerializer, Serializer};
+use rkyv::AlignedVec;
+
+/// Marks the root of a versioned archive.
+pub const MAGIC: u32 = u32::from_le_bytes(*b"LEAF");
+
+/// The layouts a leaf has been archived with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum FormatVersion {
+    /// [LegacyLeaf], before [crate::key_info::KeyInfo] grew optional fields.
+    V1 = 1,
+    /// `Type_A` with the current [crate::key_info::KeyInfo].
+    V2 = 2,
+}
+
+impl FormatVersion {
+    /// The version written by [rkyv_serialize].
+    pub const CURRENT: Self = FormatVersion::V2;
+
+    /// All versions this build reads, from the oldest to the current.
+    pub const ALL: [Self; 2] = [FormatVersion::V1, FormatVersion::V2];
+
+    /// Returns the version with `number`, or `None` if it is unknown.
+    pub fn from_u32(number: u32) -> Option<Self> {
+        Self::ALL.into_iter().find(|version| *version as u32 == number)
+    }
+}
+
+/// The root of a versioned archive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize)]
+#[archive(check_bytes)]
+pub struct VersionTag {
+    pub magic: u32,
+    pub version: u32,
+    /// Position of the archived node within the buffer.
+    pub node_pos: u64,
+}
+
+/// A validated node of any supported version.
+#[derive(Clone, Copy)]
+pub enum ArchivedNode<'a> {
+    V1(&'a ArchivedLegacyLeaf),
+    V2(&'a ArchivedType_A),
+}
+
+impl<'a> ArchivedNode<'a> {
+    pub fn version(&self) -> FormatVersion {
+        match self {
+            ArchivedNode::V1(_) => FormatVersion::V1,
+            ArchivedNode::V2(_) => FormatVersion::V2,
+        }
+    }
+
+    /// Returns the node if it is archived in the current layout, so it can
+    /// be accessed without deserializing it.
+    pub fn current(&self) -> Option<&'a ArchivedType_A> {
+        match self {
+            ArchivedNode::V2(archived) => Some(archived),
+            _ => None,
+        }
+    }
+
+    /// Deserializes the node and migrates it to the current version.
+    pub fn upgrade(&self) -> Result<Type_A> {
+        match self {
+            ArchivedNode::V1(archived) => {
+                let legacy: LegacyLeaf = codec::rkyv_deserialize_value(*archived)?;
+                Ok(Type_A { entries: legacy.into_entries() })
+            }
+            ArchivedNode::V2(archived) => codec::rkyv_deserialize(archived),
+        }
+    }
+}
+
+/// Serializes `data` in the current version.
+pub fn rkyv_serialize(data: &Type_A) -> Result<AlignedVec> {
+    rkyv_serialize_version(data, FormatVersion::CURRENT)
+}
+
+/// Serializes `data` in the layout of `version`, dropping what the layout
+/// cannot represent.
+pub fn rkyv_serialize_version(data: &Type_A, version: FormatVersion) -> Result<AlignedVec> {
+    let mut serializer = AllocSerializer::<0>::default();
+    let node_pos = match version {
+        FormatVersion::V1 => serializer.serialize_value(&LegacyLeaf::from(&data.entries)),
+        FormatVersion::V2 => serializer.serialize_value(data),
+    }
+    .map_err(Error::serialize(Codec::Rkyv))?;
+    let tag = VersionTag { magic: MAGIC, version: version as u32, node_pos: node_pos as u64 };
+    serializer.serialize_value(&tag).map_err(Error::serialize(Codec::Rkyv))?;
+    Ok(serializer.into_serializer().into_inner())
+}
+
+fn check_node<T: rkyv::Archive>(bytes: &[u8], pos: usize) -> Result<&T::Archived>
+where
+    T::Archived: for<'a> rkyv::CheckBytes<rkyv::validation::validators::DefaultValidator<'a>>,
+{
+    rkyv::check_archived_value::<T>(bytes, pos).map_err(Error::validate)
+}
+
+fn check_slices<'a>(
+    node: ArchivedNode<'a>,
+    mut slices: impl Iterator<Item = &'a ArchivedSlicedCowBytes>,
+) -> Result<ArchivedNode<'a>> {
+    if !slices.all(|slice| slice.is_in_bounds()) {
+        return Err(Error::Validate { context: "slice is out of bounds".to_string() });
+    }
+    Ok(node)
+}
+
+fn check_version(bytes: &[u8], version: FormatVersion, pos: usize) -> Result<ArchivedNode<'_>> {
+    match version {
+        FormatVersion::V1 => {
+            let archived = check_node::<LegacyLeaf>(bytes, pos)?;
+            check_slices(ArchivedNode::V1(archived), archived.entries.iter().map(|entry| &entry.value.1))
+        }
+        FormatVersion::V2 => {
+            let archived = check_node::<Type_A>(bytes, pos)?;
+            check_slices(ArchivedNode::V2(archived), archived.entries.iter().map(|entry| &entry.value.1))
+        }
+    }
+}
+
+/// Returns the tag of a versioned archive, or `None` if `bytes` was written
+/// without one.
+pub fn version_tag(bytes: &[u8]) -> Option<&ArchivedVersionTag> {
+    codec::rkyv_check_value::<VersionTag>(bytes).ok().filter(|tag| tag.magic == MAGIC)
+}
+
+/// Validates `bytes` as a leaf of any supported version, including the
+/// bounds of all slices.
+pub fn rkyv_check(bytes: &[u8]) -> Result<ArchivedNode<'_>> {
+    let tag = match version_tag(bytes) {
+        Some(tag) => tag,
+        None => {
+            let unversioned = bytes.len().saturating_sub(std::mem::size_of::<ArchivedType_A>());
+            return check_version(bytes, FormatVersion::V2, unversioned).or_else(|current| {
+                let unversioned = bytes.len().saturating_sub(std::mem::size_of::<ArchivedLegacyLeaf>());
+                check_version(bytes, FormatVersion::V1, unversioned).map_err(|_| current)
+            });
+        }
+    };
+    let (version, node_pos) = (rkyv::from_archived!(tag.version), rkyv::from_archived!(tag.node_pos));
+    let version = FormatVersion::from_u32(version).ok_or(Error::Version { version })?;
+    let pos = usize::try_from(node_pos).map_err(|_| Error::Envelope {
+        codec: Codec::Rkyv,
+        reason: format!("node position {} exceeds the address space", node_pos),
+    })?;
+    check_version(bytes, version, pos)
+}
+
+/// Reads an archive of any supported version and writes it anew in the
+/// current version.
+pub fn rkyv_migrate(bytes: &[u8]) -> Result<AlignedVec> {
+    rkyv_serialize(&rkyv_check(bytes)?.upgrade()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::archive_format::{self, ArchiveFormat};
+    use crate::test_support::sample_entries;
+    use crate::key_info::KeyInfo;
+    use crate::storage_preference::StoragePreference;
+
+    use std::{fs, path::PathBuf, time::Duration};
+
+    fn golden_dir() -> PathBuf {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("fixtures/golden")
+    }
+
+    fn read_golden(name: &str) -> AlignedVec {
+        let bytes = fs::read(golden_dir().join(name)).unwrap_or_else(|e| panic!("reading {} failed: {}", name, e));
+        let mut aligned = AlignedVec::with_capacity(bytes.len());
+        aligned.extend_from_slice(&bytes);
+        aligned
+    }
+
+    /// The node in all golden files, with optional key info fields that
+    /// version 1 cannot represent.
+    fn golden_node() -> Type_A {
+        let mut entries = sample_entries(16, 8);
+        for (number, (info, _)) in entries.values_mut().enumerate() {
+            *info = KeyInfo::new(StoragePreference::new(number as u8 % 4)).with_flags(number as u8);
+            if number % 2 == 0 {
+                info.set_sequence(number as u64);
+                info.set_ttl(Duration::from_secs(60));
+            }
+        }
+        Type_A { entries }
+    }
+
+    /// The golden node as read from an archive of `version`.
+    fn expected(version: FormatVersion) -> Type_A {
+        let mut node = golden_node();
+        if version == FormatVersion::V1 {
+            for (info, _) in node.entries.values_mut() {
+                *info = KeyInfo::new(*info.storage_preference());
+            }
+        }
+        node
+    }
+
+    /// Golden files and how to write them with the current code. Unversioned
+    /// archives are bytewise identical to the ones written before the tag was
+    /// introduced.
+    fn golden_files() -> Vec<(&'static str, FormatVersion, AlignedVec)> {
+        let node = golden_node();
+        vec![
+            (
+                "leaf_v1_unversioned.rkyv",
+                FormatVersion::V1,
+                codec::rkyv_serialize_value(&LegacyLeaf::from(&node.entries)).unwrap(),
+            ),
+            ("leaf_v2_unversioned.rkyv", FormatVersion::V2, codec::rkyv_serialize(&node).unwrap()),
+            ("leaf_v1.rkyv", FormatVersion::V1, rkyv_serialize_version(&node, FormatVersion::V1).unwrap()),
+            ("leaf_v2.rkyv", FormatVersion::V2, rkyv_serialize_version(&node, FormatVersion::V2).unwrap()),
+        ]
+    }
+
+    #[test]
+    #[cfg_attr(
+        any(feature = "archive_be", feature = "size_64", all(target_endian = "big", not(feature = "archive_le"))),
+        ignore = "the golden files are le/32-bit archives"
+    )]
+    fn golden_files_are_readable() {
+        assert!(archive_format::reads_fixtures());
+        for (name, version, _) in golden_files() {
+            let bytes = read_golden(name);
+            let archived = rkyv_check(&bytes).unwrap();
+            assert_eq!(archived.version(), version, "{}", name);
+            assert_eq!(archived.current().is_some(), version == FormatVersion::CURRENT, "{}", name);
+            assert_eq!(archived.upgrade().unwrap(), expected(version), "{}", name);
+            assert_eq!(version_tag(&bytes).is_some(), !name.contains("unversioned"), "{}", name);
+
+            let migrated = rkyv_migrate(&bytes).unwrap();
+            let archived = rkyv_check(&migrated).unwrap();
+            assert_eq!(archived.version(), FormatVersion::CURRENT);
+            assert_eq!(codec::rkyv_deserialize(archived.current().unwrap()).unwrap(), expected(version));
+        }
+    }
+
+    #[test]
+    #[cfg_attr(
+        any(feature = "archive_be", feature = "size_64", all(target_endian = "big", not(feature = "archive_le"))),
+        ignore = "the golden files are le/32-bit archives"
+    )]
+    fn layouts_match_golden_files() {
+        assert!(archive_format::reads_fixtures());
+        for (name, _, bytes) in golden_files() {
+            assert!(read_golden(name)[..] == bytes[..], "layout of {} changed, see fixtures/golden", name);
+        }
+    }
+
+    #[test]
+    fn unknown_versions_are_detected() {
+        let node = golden_node();
+        let mut serializer = AllocSerializer::<0>::default();
+        let node_pos = serializer.serialize_value(&node).unwrap() as u64;
+        serializer.serialize_value(&VersionTag { magic: MAGIC, version: 3, node_pos }).unwrap();
+        let bytes = serializer.into_serializer().into_inner();
+        assert_eq!(rkyv_check(&bytes).err().unwrap().phase(), "version");
+
+        let mut bytes = rkyv_serialize(&node).unwrap();
+        let len = bytes.len();
+        bytes[len - 8..].copy_from_slice(&(len as u64).to_le_bytes());
+        assert_eq!(rkyv_check(&bytes).err().unwrap().phase(), "validate");
+        let mut garbage = AlignedVec::new();
+        garbage.extend_from_slice(&[0xff; 64]);
+        assert_eq!(rkyv_check(&garbage).err().unwrap().phase(), "validate");
+    }
+
+    /// Writes the golden files. Only run this after adding a version, never
+    /// to make `layouts_match_golden_files` pass after changing a layout.
+    #[test]
+    #[ignore]
+    fn regenerate_golden_files() {
+        assert!(archive_format::reads_fixtures(), "golden files are written in {}", ArchiveFormat::FIXTURES);
+        fs::create_dir_all(golden_dir()).unwrap();
+        for (name, _, bytes) in golden_files() {
+            fs::write(golden_dir().join(name), &bytes).unwrap();
+        }
+    }
+}